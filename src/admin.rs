@@ -0,0 +1,343 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use actix::Addr;
+use futures::future::{join_all, try_join_all};
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn, LevelFilter};
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status};
+
+use crate::conn_manager::{ChannelManager, ForceResetChannel, GetChannel, GetPeerStatus};
+use crate::constants::{DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT};
+use crate::coordinator::rpc_failed;
+use crate::dto::{EnrichedLocationStats, ShardError};
+use crate::root_actor::{get_location, list_owned, rewrite_shards, GetPoolStats, RootActor};
+use crate::rs::rs::admin_client::AdminClient;
+use crate::rs::rs::admin_server::Admin;
+use crate::rs::rs::rs_client::RsClient;
+use crate::rs::rs::{self, GetShardRequest, InspectLocationRequest, LocationCountsRequest, LocationCountsResponse, NodeLocationCounts, ResetPeerChannelRequest, ResetPeerChannelResponse, ScrubRequest, ScrubResponse, SetLogLevelRequest, SetLogLevelResponse};
+use crate::trace::{Span, SpanKind, TraceContext};
+use crate::util::{get_owner_node_id, shard_node_id};
+use crate::validation::{validate_location_id, ValidationConfig};
+
+// Locations checked at once by a scrub, each one reads from all 7 nodes
+const SCRUB_CONCURRENCY: usize = 16;
+const TOTAL_SHARDS: usize = 6;
+
+// env_logger fixes its filter at startup, so it lets everything through and the
+// level from RUST_LOG goes into log's max level instead, where SetLogLevel can change it
+pub fn init_logging() {
+    let level = env_logger::Builder::from_default_env().build().filter();
+    env_logger::Builder::from_default_env().filter_level(LevelFilter::Trace).init();
+    log::set_max_level(level);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShardState {
+    Ok,
+    Missing,
+    // Present but not what the owner record encodes to
+    Stale,
+    // Present, with no owner record to compare against
+    Unverified,
+    Unreachable,
+    Repaired,
+}
+
+impl ShardState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShardState::Ok => "ok",
+            ShardState::Missing => "missing",
+            ShardState::Stale => "stale",
+            ShardState::Unverified => "unverified",
+            ShardState::Unreachable => "unreachable",
+            ShardState::Repaired => "repaired",
+        }
+    }
+
+    fn needs_repair(self) -> bool {
+        matches!(self, ShardState::Missing | ShardState::Stale)
+    }
+}
+
+fn shard_state(expected: Option<&[u8]>, found: Option<&[u8]>) -> ShardState {
+    match (expected, found) {
+        (_, None) => ShardState::Missing,
+        (None, Some(_)) => ShardState::Unverified,
+        (Some(expected), Some(found)) if expected == found => ShardState::Ok,
+        (Some(_), Some(_)) => ShardState::Stale,
+    }
+}
+
+// The 4 data and 2 parity shards a record is written as
+fn encode(record: &EnrichedLocationStats) -> Result<Vec<Vec<u8>>, ShardError> {
+    let shards = record.to_shards()?;
+    let recovery = reed_solomon_simd::encode(4, 2, shards.as_slice())
+        .map_err(|err| ShardError::EncodingError(err.to_string()))?;
+    Ok([shards.to_vec(), recovery].concat())
+}
+
+pub struct Placement {
+    pub index: usize,
+    pub node_id: u32,
+    pub state: ShardState,
+    pub error: Option<String>,
+}
+
+// A location's owner record next to what each shard holder has
+pub struct Inspection {
+    pub location_id: String,
+    pub owner_id: u32,
+    pub record: Option<EnrichedLocationStats>,
+    pub shards: Vec<Placement>,
+    pub error: Option<String>,
+}
+
+impl Inspection {
+    pub fn is_healthy(&self) -> bool {
+        self.error.is_none() && self.record.is_some() && self.shards.iter().all(|shard| shard.state == ShardState::Ok)
+    }
+
+    fn to_proto(&self) -> rs::LocationInspection {
+        rs::LocationInspection {
+            location_id: self.location_id.clone(),
+            owner_node_id: self.owner_id,
+            owner_record: self.record.as_ref().map(|record| record.to_proto()),
+            shards: self.shards.iter().map(|shard| rs::ShardPlacement {
+                index: shard.index as u32,
+                node_id: shard.node_id,
+                state: shard.state.as_str().to_owned(),
+                error: shard.error.clone().unwrap_or_default(),
+            }).collect(),
+            error: self.error.clone().unwrap_or_default(),
+        }
+    }
+}
+
+// Operator actions against this node: scrubs of the locations it owns, inspection
+// of any location, and runtime knobs
+pub struct AdminApi {
+    pub current_node: u32,
+    pub root_actor: Vec<Addr<RootActor>>,
+    pub channel_manager: Arc<Addr<ChannelManager>>,
+    pub validation: ValidationConfig,
+}
+
+impl AdminApi {
+    async fn channel(&self, node_id: u32) -> Result<Channel, ShardError> {
+        self.channel_manager
+            .send(GetChannel(node_id))
+            .await?
+            .map_err(ShardError::ChannelError)
+    }
+
+    // getShardRequest answers with whatever the node holds; NotFound means nothing
+    async fn read_node(&self, node_id: u32, location_id: &str, trace: &TraceContext) -> Result<Option<rs::GetShardResponse>, ShardError> {
+        let mut client = RsClient::new(self.channel(node_id).await?);
+        let mut request = Request::new(GetShardRequest { location_id: location_id.to_owned() });
+        trace.inject(&mut request);
+        match client.get_shard_request(request).await {
+            Ok(res) => Ok(Some(res.into_inner())),
+            Err(status) if status.code() == tonic::Code::NotFound => Ok(None),
+            Err(status) => Err(rpc_failed(&self.channel_manager, node_id, status)),
+        }
+    }
+
+    async fn owner_record(&self, location_id: &str, owner_id: u32, trace: &TraceContext) -> Result<Option<EnrichedLocationStats>, ShardError> {
+        if owner_id == self.current_node {
+            return match get_location(&self.root_actor, location_id).await {
+                Ok(record) => Ok(Some(record)),
+                Err(ShardError::NotFoundError(_)) => Ok(None),
+                Err(err) => Err(err),
+            };
+        }
+        let res = self.read_node(owner_id, location_id, trace).await?;
+        Ok(res.and_then(|res| res.location_stats).map(EnrichedLocationStats::from_proto))
+    }
+
+    pub async fn inspect(&self, location_id: String, trace: TraceContext) -> Inspection {
+        let owner_id = get_owner_node_id(location_id.clone());
+        let (record, mut error) = match self.owner_record(&location_id, owner_id, &trace).await {
+            Ok(record) => (record, None),
+            Err(err) => (None, Some(format!("owner record: {}", err))),
+        };
+        let expected = match record.as_ref().map(encode).transpose() {
+            Ok(expected) => expected,
+            Err(err) => {
+                error = Some(format!("encoding owner record: {}", err));
+                None
+            }
+        };
+
+        let (location_id_ref, trace_ref, expected_ref) = (&location_id, &trace, &expected);
+        let shards = join_all((0..TOTAL_SHARDS).map(|index| async move {
+            let node_id = shard_node_id(owner_id, index);
+            let expected = expected_ref.as_ref().map(|shards| shards[index].as_slice());
+            let (state, error) = match self.read_node(node_id, location_id_ref, trace_ref).await {
+                Ok(res) => (shard_state(expected, res.and_then(|res| res.shard).as_deref()), None),
+                Err(err) => (ShardState::Unreachable, Some(err.to_string())),
+            };
+            Placement { index, node_id, state, error }
+        })).await;
+
+        Inspection { location_id, owner_id, record, shards, error }
+    }
+
+    // Inspects an owned location and, when asked, rewrites its shards if any are
+    // missing or stale. Unreachable holders are left for a later scrub.
+    async fn scrub_location(&self, location_id: String, repair: bool, trace: TraceContext) -> Inspection {
+        let mut inspection = self.inspect(location_id, trace.clone()).await;
+        if !repair || inspection.record.is_none() || !inspection.shards.iter().any(|shard| shard.state.needs_repair()) {
+            return inspection;
+        }
+
+        match rewrite_shards(&self.root_actor, self.channel_manager.clone(), inspection.location_id.clone(), trace).await {
+            Ok(()) => {
+                info!("Repaired shards of {}", inspection.location_id);
+                for shard in inspection.shards.iter_mut().filter(|shard| shard.state.needs_repair()) {
+                    shard.state = ShardState::Repaired;
+                }
+            }
+            Err(err) => {
+                error!("Failed to repair shards of {}: {}", inspection.location_id, err);
+                inspection.error = Some(format!("repair: {}", err));
+            }
+        }
+        inspection
+    }
+
+    async fn local_counts(&self) -> Result<NodeLocationCounts, ShardError> {
+        let pools = try_join_all(self.root_actor.iter().map(|root_actor| root_actor.send(GetPoolStats))).await?;
+        Ok(NodeLocationCounts {
+            node_id: self.current_node,
+            owned: pools.iter().map(|pool| pool.owned as u64).sum(),
+            locations: pools.iter().map(|pool| pool.locations as u64).sum(),
+            error: String::new(),
+        })
+    }
+
+    async fn remote_counts(&self, node_id: u32) -> NodeLocationCounts {
+        let failed = |error: String| NodeLocationCounts { node_id, error, ..Default::default() };
+        let channel = match self.channel(node_id).await {
+            Ok(channel) => channel,
+            Err(err) => return failed(err.to_string()),
+        };
+        match AdminClient::new(channel).location_counts(Request::new(LocationCountsRequest { local_only: true })).await {
+            Ok(res) => res.into_inner().nodes.into_iter().next()
+                .unwrap_or_else(|| failed("no counts in response".to_owned())),
+            Err(status) => failed(rpc_failed(&self.channel_manager, node_id, status).to_string()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminApi {
+    async fn scrub(&self, request: Request<ScrubRequest>) -> Result<Response<ScrubResponse>, Status> {
+        let mut span = Span::start("admin.scrub", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let data = request.into_inner();
+        let limit = match data.limit {
+            0 => DEFAULT_LIST_LIMIT,
+            limit => (limit as usize).min(MAX_LIST_LIMIT),
+        };
+        span.set_attribute("scrub.repair", data.repair);
+
+        let location_ids = list_owned(&self.root_actor, data.after, limit).await?;
+        let next = if location_ids.len() == limit { location_ids.last().cloned() } else { None };
+        let trace = span.context();
+        let inspections: Vec<Inspection> = stream::iter(location_ids.into_iter()
+            .map(|location_id| self.scrub_location(location_id, data.repair, trace.clone())))
+            .buffered(SCRUB_CONCURRENCY)
+            .collect()
+            .await;
+
+        let checked = inspections.len() as u32;
+        let healthy = inspections.iter().filter(|inspection| inspection.is_healthy()).count() as u32;
+        let repaired = inspections.iter()
+            .filter(|inspection| inspection.shards.iter().any(|shard| shard.state == ShardState::Repaired))
+            .count() as u32;
+        span.set_attribute("scrub.checked", checked);
+        span.set_attribute("scrub.damaged", checked - healthy);
+        info!("Scrubbed {} locations: {} healthy, {} repaired", checked, healthy, repaired);
+
+        Ok(Response::new(ScrubResponse {
+            checked,
+            healthy,
+            repaired,
+            damaged: inspections.iter().filter(|inspection| !inspection.is_healthy()).map(Inspection::to_proto).collect(),
+            next,
+        }))
+    }
+
+    async fn inspect_location(&self, request: Request<InspectLocationRequest>) -> Result<Response<rs::LocationInspection>, Status> {
+        let span = Span::start("admin.inspect_location", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let location_id = request.into_inner().location_id;
+        validate_location_id(&location_id, &self.validation)?;
+        let inspection = self.inspect(location_id, span.context()).await;
+        Ok(Response::new(inspection.to_proto()))
+    }
+
+    async fn location_counts(&self, request: Request<LocationCountsRequest>) -> Result<Response<LocationCountsResponse>, Status> {
+        let local = self.local_counts().await?;
+        if request.into_inner().local_only {
+            return Ok(Response::new(LocationCountsResponse { nodes: vec![local] }));
+        }
+
+        let peers = self.channel_manager.send(GetPeerStatus).await.map_err(ShardError::from)?;
+        let remote = join_all(peers.iter()
+            .filter(|peer| !peer.is_self)
+            .map(|peer| self.remote_counts(peer.node_id))).await;
+
+        let mut nodes: Vec<NodeLocationCounts> = std::iter::once(local).chain(remote).collect();
+        nodes.sort_by_key(|counts| counts.node_id);
+        Ok(Response::new(LocationCountsResponse { nodes }))
+    }
+
+    async fn reset_peer_channel(&self, request: Request<ResetPeerChannelRequest>) -> Result<Response<ResetPeerChannelResponse>, Status> {
+        let node_id = request.into_inner().node_id;
+        if node_id == self.current_node {
+            return Err(Status::invalid_argument("can't reset the channel to this node"));
+        }
+        warn!("Channel reset to node {} requested by an operator", node_id);
+        self.channel_manager.send(ForceResetChannel(node_id)).await.map_err(ShardError::from)?;
+        Ok(Response::new(ResetPeerChannelResponse {}))
+    }
+
+    async fn set_log_level(&self, request: Request<SetLogLevelRequest>) -> Result<Response<SetLogLevelResponse>, Status> {
+        let requested = request.into_inner().level;
+        let level = LevelFilter::from_str(requested.trim())
+            .map_err(|_| Status::invalid_argument(format!("unknown log level '{}'", requested)))?;
+        let previous = log::max_level();
+        // Logged before the change so lowering the level doesn't hide it
+        warn!("Log level changed from {} to {}", previous, level);
+        log::set_max_level(level);
+        Ok(Response::new(SetLogLevelResponse {
+            previous: previous.as_str().to_lowercase(),
+            level: level.as_str().to_lowercase(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_state() {
+        assert_eq!(shard_state(Some(&[1, 2]), Some(&[1, 2])), ShardState::Ok);
+        assert_eq!(shard_state(Some(&[1, 2]), Some(&[1, 3])), ShardState::Stale);
+        assert_eq!(shard_state(Some(&[1, 2]), None), ShardState::Missing);
+        assert_eq!(shard_state(None, Some(&[1, 2])), ShardState::Unverified);
+        assert!(ShardState::Stale.needs_repair());
+        assert!(!ShardState::Unreachable.needs_repair());
+    }
+
+    #[test]
+    fn test_shards_skip_the_owner() {
+        for owner_id in 0..7 {
+            let holders: Vec<u32> = (0..TOTAL_SHARDS).map(|index| shard_node_id(owner_id, index)).collect();
+            assert!(!holders.contains(&owner_id));
+            assert_eq!(holders.iter().collect::<std::collections::HashSet<_>>().len(), TOTAL_SHARDS);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, HashMap};
+use actix::Addr;
+use futures::future::try_join_all;
+use serde::Serialize;
+
+use crate::dto::{EnrichedLocationStats, ShardError};
+use crate::query::{read_owned_page, StatField};
+use crate::root_actor::RootActor;
+use crate::rs::rs;
+
+const FIELDS: [StatField; 3] = [StatField::SeismicActivity, StatField::TemperatureC, StatField::RadiationLevel];
+// Percentiles come out within this fraction of the true value
+const RELATIVE_ACCURACY: f64 = 0.01;
+// Magnitudes at or below this count as zero
+const MIN_MAGNITUDE: f64 = 1e-9;
+
+// Ratio between the bounds of consecutive histogram buckets
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+// Log-scale histogram of values by magnitude. Its size grows with the spread of the
+// values, not their number, and merging two is adding their bucket counts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    pub positive: BTreeMap<i32, u64>,
+    pub negative: BTreeMap<i32, u64>,
+    pub zero_count: u64,
+}
+
+impl Histogram {
+    fn bucket(magnitude: f64) -> i32 {
+        (magnitude.ln() / gamma().ln()).ceil() as i32
+    }
+
+    // The point of a bucket with the least relative error to anything in it
+    fn value(bucket: i32) -> f64 {
+        2.0 * gamma().powi(bucket) / (gamma() + 1.0)
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value.abs() <= MIN_MAGNITUDE {
+            self.zero_count += 1;
+        } else if value > 0.0 {
+            *self.positive.entry(Self::bucket(value)).or_default() += 1;
+        } else {
+            *self.negative.entry(Self::bucket(-value)).or_default() += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: Histogram) {
+        for (bucket, count) in other.positive {
+            *self.positive.entry(bucket).or_default() += count;
+        }
+        for (bucket, count) in other.negative {
+            *self.negative.entry(bucket).or_default() += count;
+        }
+        self.zero_count += other.zero_count;
+    }
+
+    // Buckets from the lowest value to the highest
+    fn ascending(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        let negative = self.negative.iter().rev().map(|(bucket, count)| (-Self::value(*bucket), *count));
+        let zero = std::iter::once((0.0, self.zero_count));
+        let positive = self.positive.iter().map(|(bucket, count)| (Self::value(*bucket), *count));
+        negative.chain(zero).chain(positive)
+    }
+
+    // Nearest-rank percentile out of `count` values
+    fn percentile(&self, count: u64, p: f64) -> Option<f64> {
+        if count == 0 {
+            return None;
+        }
+        let rank = (((p / 100.0) * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        self.ascending().find(|(_, count)| {
+            seen += count;
+            seen >= rank
+        }).map(|(value, _)| value)
+    }
+}
+
+// Mergeable aggregate of one stat field. Percentiles come from a histogram, so
+// partials stay small however many locations they cover.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldPartial {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub histogram: Histogram,
+}
+
+impl Default for FieldPartial {
+    fn default() -> Self {
+        FieldPartial {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            histogram: Histogram::default(),
+        }
+    }
+}
+
+impl FieldPartial {
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.histogram.add(value);
+    }
+
+    pub fn merge(&mut self, other: FieldPartial) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.histogram.merge(other.histogram);
+    }
+
+    // A bucket's value can overshoot the values in it, min and max are exact
+    fn percentile(&self, p: f64) -> Option<f64> {
+        self.histogram.percentile(self.count, p).map(|value| value.clamp(self.min, self.max))
+    }
+
+    pub fn summary(self) -> FieldSummary {
+        if self.count == 0 {
+            return FieldSummary::default();
+        }
+        FieldSummary {
+            min: Some(self.min),
+            max: Some(self.max),
+            avg: Some(self.sum / self.count as f64),
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+        }
+    }
+
+    pub fn to_proto(&self) -> rs::FieldPartial {
+        rs::FieldPartial {
+            count: self.count,
+            sum: self.sum,
+            min: self.min,
+            max: self.max,
+            values: Vec::new(),
+            positive_buckets: self.histogram.positive.iter().map(|(bucket, count)| (*bucket, *count)).collect(),
+            negative_buckets: self.histogram.negative.iter().map(|(bucket, count)| (*bucket, *count)).collect(),
+            zero_count: self.histogram.zero_count,
+        }
+    }
+
+    pub fn from_proto(partial: rs::FieldPartial) -> Self {
+        let buckets = |buckets: HashMap<i32, u64>| buckets.into_iter().collect();
+        let mut histogram = Histogram {
+            positive: buckets(partial.positive_buckets),
+            negative: buckets(partial.negative_buckets),
+            zero_count: partial.zero_count,
+        };
+        // Older nodes send raw values, which count, sum, min and max already cover
+        partial.values.into_iter().for_each(|value| histogram.add(value));
+        FieldPartial {
+            count: partial.count,
+            sum: partial.sum,
+            min: partial.min,
+            max: partial.max,
+            histogram,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GroupPartial {
+    pub count: u64,
+    pub fields: [FieldPartial; 3],
+}
+
+impl GroupPartial {
+    pub fn add(&mut self, stats: &EnrichedLocationStats) {
+        self.count += 1;
+        for (partial, field) in self.fields.iter_mut().zip(FIELDS) {
+            partial.add(field.value(stats));
+        }
+    }
+
+    pub fn merge(&mut self, other: GroupPartial) {
+        self.count += other.count;
+        for (partial, other) in self.fields.iter_mut().zip(other.fields) {
+            partial.merge(other);
+        }
+    }
+
+    pub fn summary(self) -> GroupSummary {
+        let [seismic_activity, temperature_c, radiation_level] = self.fields;
+        GroupSummary {
+            count: self.count,
+            seismic_activity: seismic_activity.summary(),
+            temperature_c: temperature_c.summary(),
+            radiation_level: radiation_level.summary(),
+        }
+    }
+
+    pub fn to_proto(&self, key: &str) -> rs::GroupPartial {
+        rs::GroupPartial {
+            key: key.to_owned(),
+            count: self.count,
+            seismic_activity: Some(self.fields[0].to_proto()),
+            temperature_c: Some(self.fields[1].to_proto()),
+            radiation_level: Some(self.fields[2].to_proto()),
+        }
+    }
+
+    pub fn from_proto(group: rs::GroupPartial) -> (String, Self) {
+        let field = |partial: Option<rs::FieldPartial>| partial.map(FieldPartial::from_proto).unwrap_or_default();
+        let partial = GroupPartial {
+            count: group.count,
+            fields: [field(group.seismic_activity), field(group.temperature_c), field(group.radiation_level)],
+        };
+        (group.key, partial)
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct FieldSummary {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    pub p50: Option<f64>,
+    pub p90: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupSummary {
+    pub count: u64,
+    pub seismic_activity: FieldSummary,
+    pub temperature_c: FieldSummary,
+    pub radiation_level: FieldSummary,
+}
+
+// Which owner records to aggregate and how to group them
+#[derive(Clone, Debug, Default)]
+pub struct AggregateSpec {
+    pub prefix: Option<String>,
+    // Group by the first N characters of the location id, 0 for no grouping
+    pub group_prefix_len: usize,
+}
+
+impl AggregateSpec {
+    pub fn to_proto(&self) -> rs::AggregateRequest {
+        rs::AggregateRequest {
+            prefix: self.prefix.clone().unwrap_or_default(),
+            group_prefix_len: self.group_prefix_len as u32,
+        }
+    }
+
+    pub fn from_proto(req: &rs::AggregateRequest) -> Self {
+        AggregateSpec {
+            prefix: (!req.prefix.is_empty()).then(|| req.prefix.clone()),
+            group_prefix_len: req.group_prefix_len as usize,
+        }
+    }
+
+    fn matches(&self, location_id: &str) -> bool {
+        self.prefix.as_ref().map_or(true, |prefix| location_id.starts_with(prefix.as_str()))
+    }
+
+    fn group_key(&self, location_id: &str) -> String {
+        location_id.chars().take(self.group_prefix_len).collect()
+    }
+}
+
+pub type Partials = BTreeMap<String, GroupPartial>;
+
+pub fn merge(into: &mut Partials, other: Partials) {
+    for (key, partial) in other {
+        into.entry(key).or_default().merge(partial);
+    }
+}
+
+async fn aggregate_root_actor(root_actor: &Addr<RootActor>, spec: &AggregateSpec) -> Result<Partials, ShardError> {
+    let mut partials = Partials::new();
+    let mut cursor = None;
+
+    while let Some((last, records, exhausted)) = read_owned_page(root_actor, cursor).await? {
+        cursor = Some(last);
+        for record in records.iter().filter(|record| spec.matches(&record.location_id)) {
+            partials.entry(spec.group_key(&record.location_id)).or_default().add(&record.stats);
+        }
+        if exhausted {
+            break;
+        }
+    }
+
+    Ok(partials)
+}
+
+// Partial aggregates over the owner records held by this node
+pub async fn aggregate(pool: &[Addr<RootActor>], spec: &AggregateSpec) -> Result<Partials, ShardError> {
+    let mut partials = Partials::new();
+    for other in try_join_all(pool.iter().map(|root_actor| aggregate_root_actor(root_actor, spec))).await? {
+        merge(&mut partials, other);
+    }
+    Ok(partials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::stats;
+
+    #[test]
+    fn test_merged_partials_match_single_pass() {
+        let readings: Vec<EnrichedLocationStats> = (1..=10).map(|i| stats(i as f64, -(i as f64), i as f64 / 10.0)).collect();
+
+        let mut whole = GroupPartial::default();
+        readings.iter().for_each(|r| whole.add(r));
+
+        let (left, right) = readings.split_at(3);
+        let mut merged = GroupPartial::default();
+        left.iter().for_each(|r| merged.add(r));
+        let mut other = GroupPartial::default();
+        right.iter().for_each(|r| other.add(r));
+        merged.merge(other);
+
+        let (whole, merged) = (whole.summary(), merged.summary());
+        let close = |value: Option<f64>, expected: f64| (value.unwrap() - expected).abs() <= expected.abs() * RELATIVE_ACCURACY;
+        assert_eq!(merged.count, 10);
+        assert_eq!(merged.seismic_activity, whole.seismic_activity);
+        assert_eq!(merged.seismic_activity.avg, Some(5.5));
+        assert!(close(merged.seismic_activity.p50, 5.0));
+        assert!(close(merged.seismic_activity.p90, 9.0));
+        assert!(close(merged.temperature_c.p50, -6.0));
+        assert_eq!(merged.temperature_c.min, Some(-10.0));
+        assert_eq!(merged.radiation_level.max, Some(1.0));
+    }
+
+    #[test]
+    fn test_histogram_stays_small_and_accurate() {
+        let mut partial = FieldPartial::default();
+        for i in 0..100_000 {
+            partial.add((i % 1000) as f64 / 10.0);
+        }
+        assert!(partial.histogram.positive.len() < 1000);
+        assert_eq!(partial.histogram.zero_count, 100);
+
+        let summary = partial.summary();
+        assert_eq!(summary.min, Some(0.0));
+        assert_eq!(summary.max, Some(99.9));
+        assert!((summary.p50.unwrap() - 49.9).abs() <= 49.9 * RELATIVE_ACCURACY);
+        assert!((summary.p99.unwrap() - 98.9).abs() <= 98.9 * RELATIVE_ACCURACY);
+    }
+
+    #[test]
+    fn test_legacy_values_fold_into_histogram() {
+        let mut expected = FieldPartial::default();
+        [1.0, -2.0, 3.0].into_iter().for_each(|value| expected.add(value));
+        let legacy = rs::FieldPartial { count: 3, sum: 2.0, min: -2.0, max: 3.0, values: vec![1.0, -2.0, 3.0], ..Default::default() };
+        assert_eq!(FieldPartial::from_proto(legacy), expected);
+    }
+
+    #[test]
+    fn test_empty_summary_has_no_values() {
+        let summary = GroupPartial::default().summary();
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.temperature_c, FieldSummary::default());
+    }
+
+    #[test]
+    fn test_proto_roundtrip_and_grouping() {
+        let mut partial = GroupPartial::default();
+        partial.add(&stats(1.0, 2.0, 3.0));
+        let (key, decoded) = GroupPartial::from_proto(partial.to_proto("site-a"));
+        assert_eq!(key, "site-a");
+        assert_eq!(decoded, partial);
+
+        let spec = AggregateSpec { prefix: Some("site".to_owned()), group_prefix_len: 6 };
+        assert!(spec.matches("site-b/7"));
+        assert!(!spec.matches("lab-1"));
+        assert_eq!(spec.group_key("site-b/7"), "site-b");
+    }
+}
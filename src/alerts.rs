@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use actix::prelude::*;
+use awc::Client;
+use futures::future::join_all;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tonic::Request;
+use uuid::Uuid;
+
+use crate::conn_manager::{ChannelManager, GetAllChannels};
+use crate::dto::{EnrichedLocationStats, ShardError};
+use crate::query::Predicate;
+use crate::rs::rs::{self, rs_client::RsClient, ListAlertRulesRequest};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_ALERT_HISTORY: usize = 1000;
+const DEFAULT_COOLDOWN_SECS: u64 = 60;
+// Give peers time to come up before pulling their rules
+const RULE_SYNC_DELAY: Duration = Duration::from_secs(3);
+// Rules are pulled again after that, to pick up changes a node missed while unreachable
+const RULE_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+const RULE_SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+// How long a deleted rule is remembered; a node cut off for longer may bring it back
+const DELETED_RULE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const COOLDOWN_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+static ALERTS: OnceLock<Alerts> = OnceLock::new();
+
+// Body of POST /alerts/rules
+#[derive(Deserialize, Clone)]
+pub struct AlertRuleSpec {
+    pub field: String,
+    // A query comparator such as gt:5 or between:1,2
+    pub condition: String,
+    pub location_pattern: Option<String>,
+    pub webhook_url: String,
+    pub cooldown_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AlertRule {
+    pub id: String,
+    pub field: String,
+    pub condition: String,
+    // Location ids to watch, `*` matches any run of characters
+    pub location_pattern: String,
+    pub webhook_url: String,
+    pub cooldown_secs: u64,
+}
+
+impl AlertRule {
+    pub fn from_spec(spec: AlertRuleSpec) -> Result<Self, ShardError> {
+        let rule = AlertRule {
+            id: Uuid::new_v4().to_string(),
+            field: spec.field,
+            condition: spec.condition,
+            location_pattern: spec.location_pattern.filter(|p| !p.is_empty()).unwrap_or_else(|| "*".to_owned()),
+            webhook_url: spec.webhook_url,
+            cooldown_secs: spec.cooldown_secs.unwrap_or(DEFAULT_COOLDOWN_SECS),
+        };
+        rule.compile()?;
+        Ok(rule)
+    }
+
+    fn compile(&self) -> Result<CompiledRule, ShardError> {
+        let predicate = Predicate::parse(&self.field, &self.condition)?;
+        if !(self.webhook_url.starts_with("http://") || self.webhook_url.starts_with("https://")) {
+            return Err(ShardError::InvalidInput(format!("webhook_url must be an http(s) URL, got {}", self.webhook_url)));
+        }
+        Ok(CompiledRule { rule: self.clone(), predicate })
+    }
+
+    pub fn to_proto(&self) -> rs::AlertRule {
+        rs::AlertRule {
+            id: self.id.clone(),
+            field: self.field.clone(),
+            condition: self.condition.clone(),
+            location_pattern: self.location_pattern.clone(),
+            webhook_url: self.webhook_url.clone(),
+            cooldown_secs: self.cooldown_secs,
+        }
+    }
+
+    pub fn from_proto(rule: rs::AlertRule) -> Self {
+        AlertRule {
+            id: rule.id,
+            field: rule.field,
+            condition: rule.condition,
+            location_pattern: rule.location_pattern,
+            webhook_url: rule.webhook_url,
+            cooldown_secs: rule.cooldown_secs,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CompiledRule {
+    rule: AlertRule,
+    predicate: Predicate,
+}
+
+impl CompiledRule {
+    fn matches(&self, location_id: &str, stats: &EnrichedLocationStats) -> bool {
+        glob_match(&self.rule.location_pattern, location_id) && self.predicate.matches(stats)
+    }
+}
+
+// Rules by id, and the ids of recently deleted ones so a sync doesn't revive them.
+// Rules never change once created, so merging two nodes' sets is order-independent.
+#[derive(Default)]
+struct Rules {
+    active: BTreeMap<String, CompiledRule>,
+    deleted: HashMap<String, Instant>,
+}
+
+impl Rules {
+    fn insert(&mut self, compiled: CompiledRule) -> bool {
+        if self.deleted.contains_key(&compiled.rule.id) || self.active.contains_key(&compiled.rule.id) {
+            return false;
+        }
+        self.active.insert(compiled.rule.id.clone(), compiled);
+        true
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        self.deleted.insert(id.to_owned(), Instant::now());
+        self.active.remove(id).is_some()
+    }
+
+    // Folds in a peer's rules and deletions, returning how many rules were added and removed
+    fn merge(&mut self, rules: Vec<AlertRule>, deleted_ids: Vec<String>) -> (usize, usize) {
+        let removed = deleted_ids.iter().filter(|id| self.remove(id)).count();
+        let added = rules.into_iter()
+            .filter_map(|rule| rule.compile().ok())
+            .map(|compiled| self.insert(compiled))
+            .filter(|added| *added)
+            .count();
+        (added, removed)
+    }
+
+    fn prune_deleted(&mut self) {
+        self.deleted.retain(|_, deleted| deleted.elapsed() < DELETED_RULE_TTL);
+    }
+}
+
+type RuleSet = Arc<RwLock<Rules>>;
+
+// `*` matches any run of characters, everything else matches itself
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || !text[first.len()..].ends_with(last) {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+// Response to creating or deleting a rule; unavailable nodes did not get the change
+#[derive(Serialize)]
+pub struct AlertRuleChange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<AlertRule>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_nodes: Vec<u32>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "delivered" => DeliveryStatus::Delivered,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AlertEvent {
+    pub id: u64,
+    pub node_id: u32,
+    pub rule_id: String,
+    pub location_id: String,
+    pub field: String,
+    pub condition: String,
+    pub value: f64,
+    pub modification_count: i64,
+    pub fired_at_ms: u64,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AlertEvent {
+    pub fn to_proto(&self) -> rs::AlertEvent {
+        rs::AlertEvent {
+            id: self.id,
+            node_id: self.node_id,
+            rule_id: self.rule_id.clone(),
+            location_id: self.location_id.clone(),
+            field: self.field.clone(),
+            condition: self.condition.clone(),
+            value: self.value,
+            modification_count: self.modification_count,
+            fired_at_ms: self.fired_at_ms,
+            status: self.status.as_str().to_owned(),
+            attempts: self.attempts,
+            error: self.error.clone(),
+        }
+    }
+
+    pub fn from_proto(event: rs::AlertEvent) -> Self {
+        AlertEvent {
+            id: event.id,
+            node_id: event.node_id,
+            rule_id: event.rule_id,
+            location_id: event.location_id,
+            field: event.field,
+            condition: event.condition,
+            value: event.value,
+            modification_count: event.modification_count,
+            fired_at_ms: event.fired_at_ms,
+            status: DeliveryStatus::parse(&event.status),
+            attempts: event.attempts,
+            error: event.error,
+        }
+    }
+}
+
+// Body of GET /alerts/history; unavailable nodes' alerts are missing from it
+#[derive(Serialize)]
+pub struct AlertHistory {
+    pub events: Vec<AlertEvent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_nodes: Vec<u32>,
+}
+
+// Merges the histories of several nodes, keeping the newest `limit` alerts
+pub fn merge_history(mut events: Vec<AlertEvent>, limit: usize) -> Vec<AlertEvent> {
+    events.sort_by(|a, b| b.fired_at_ms.cmp(&a.fired_at_ms).then(a.node_id.cmp(&b.node_id)).then(b.id.cmp(&a.id)));
+    events.truncate(limit);
+    events
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    event: &'a AlertEvent,
+    stats: &'a EnrichedLocationStats,
+}
+
+// Delivers fired alerts to their webhooks and keeps a bounded history of them
+pub struct AlertManager {
+    node_id: u32,
+    rules: RuleSet,
+    cooldowns: HashMap<(String, String), Instant>, // (rule id, location id) -> last fired
+    history: VecDeque<AlertEvent>,
+    next_id: u64,
+    client: Client,
+    retry_backoff: Duration,
+    channel_manager: Option<Addr<ChannelManager>>,
+}
+
+impl AlertManager {
+    fn new(node_id: u32, rules: RuleSet, channel_manager: Option<Addr<ChannelManager>>) -> Self {
+        AlertManager {
+            node_id,
+            rules,
+            cooldowns: HashMap::new(),
+            history: VecDeque::new(),
+            next_id: 0,
+            client: Client::default(),
+            retry_backoff: WEBHOOK_RETRY_BACKOFF,
+            channel_manager,
+        }
+    }
+
+    fn prune_cooldowns(&mut self) {
+        let mut rules = self.rules.write().unwrap_or_else(|err| err.into_inner());
+        rules.prune_deleted();
+        self.cooldowns.retain(|(rule_id, _), fired| {
+            rules.active.get(rule_id).map_or(false, |r| fired.elapsed() < Duration::from_secs(r.rule.cooldown_secs))
+        });
+    }
+
+    // Merges every peer's rules and deletions into ours, so a restarted node catches up
+    // and one that missed a change while unreachable converges
+    fn sync_rules(&mut self, ctx: &mut Context<Self>) {
+        let Some(channel_manager) = self.channel_manager.clone() else {
+            return;
+        };
+        let rules = self.rules.clone();
+
+        let sync = async move {
+            let Ok(Ok((current_node, channels))) = channel_manager.send(GetAllChannels).await else {
+                return;
+            };
+            let pulls = channels.into_iter().filter(|(node_id, _)| *node_id != current_node).map(|(node_id, channel)| async move {
+                let mut request = Request::new(ListAlertRulesRequest {});
+                request.set_timeout(RULE_SYNC_TIMEOUT);
+                (node_id, RsClient::new(channel).list_alert_rules(request).await)
+            });
+            for (node_id, result) in join_all(pulls).await {
+                match result {
+                    Ok(res) => {
+                        let res = res.into_inner();
+                        let synced = res.rules.into_iter().map(AlertRule::from_proto).collect();
+                        let (added, removed) = rules.write().unwrap_or_else(|err| err.into_inner()).merge(synced, res.deleted_ids);
+                        if added + removed > 0 {
+                            info!("Alert rule sync with node {} added {} and removed {} rules", node_id, added, removed);
+                        }
+                    }
+                    Err(status) => warn!("Failed to sync alert rules from node {}: {}", node_id, status),
+                }
+            }
+        };
+        ctx.spawn(sync.into_actor(self));
+    }
+
+    fn record_delivery(&mut self, id: u64, attempts: u32, result: Result<(), String>) {
+        let Some(event) = self.history.iter_mut().find(|event| event.id == id) else {
+            return;
+        };
+        event.attempts = attempts;
+        match result {
+            Ok(()) => event.status = DeliveryStatus::Delivered,
+            Err(err) => {
+                error!("Failed to deliver alert {} for {} after {} attempts: {}", id, event.location_id, attempts, err);
+                event.status = DeliveryStatus::Failed;
+                event.error = Some(err);
+            }
+        }
+    }
+}
+
+async fn deliver(client: Client, url: String, payload: serde_json::Value, backoff: Duration) -> (u32, Result<(), String>) {
+    let mut last_err = String::new();
+    for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+        match client.post(url.as_str()).timeout(WEBHOOK_TIMEOUT).send_json(&payload).await {
+            Ok(res) if res.status().is_success() => return (attempt, Ok(())),
+            Ok(res) => last_err = format!("webhook returned {}", res.status()),
+            Err(err) => last_err = err.to_string(),
+        }
+        if attempt < WEBHOOK_MAX_ATTEMPTS {
+            actix_rt::time::sleep(backoff * 2u32.pow(attempt - 1)).await;
+        }
+    }
+    (WEBHOOK_MAX_ATTEMPTS, Err(last_err))
+}
+
+impl Actor for AlertManager {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("AlertManager started");
+        ctx.run_later(RULE_SYNC_DELAY, |act, ctx| {
+            act.sync_rules(ctx);
+            ctx.run_interval(RULE_SYNC_INTERVAL, |act, ctx| act.sync_rules(ctx));
+        });
+        ctx.run_interval(COOLDOWN_PRUNE_INTERVAL, |act, _ctx| act.prune_cooldowns());
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Fire {
+    rule: AlertRule,
+    value: f64,
+    location_id: String,
+    stats: EnrichedLocationStats,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<AlertEvent>")]
+pub struct GetHistory(pub usize);
+
+impl Handler<Fire> for AlertManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Fire, ctx: &mut Context<Self>) -> Self::Result {
+        let key = (msg.rule.id.clone(), msg.location_id.clone());
+        if let Some(fired) = self.cooldowns.get(&key) {
+            if fired.elapsed() < Duration::from_secs(msg.rule.cooldown_secs) {
+                return;
+            }
+        }
+        self.cooldowns.insert(key, Instant::now());
+
+        self.next_id += 1;
+        let event = AlertEvent {
+            id: self.next_id,
+            node_id: self.node_id,
+            rule_id: msg.rule.id,
+            location_id: msg.location_id,
+            field: msg.rule.field,
+            condition: msg.rule.condition,
+            value: msg.value,
+            modification_count: msg.stats.modification_count,
+            fired_at_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            error: None,
+        };
+        info!("Alert {} fired for {}: {} {} (value {})", event.rule_id, event.location_id, event.field, event.condition, event.value);
+
+        let payload = serde_json::to_value(WebhookPayload { event: &event, stats: &msg.stats }).unwrap_or_default();
+        let id = event.id;
+        if self.history.len() >= MAX_ALERT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(event);
+
+        let delivery = deliver(self.client.clone(), msg.rule.webhook_url, payload, self.retry_backoff);
+        ctx.spawn(delivery.into_actor(self).map(move |(attempts, result), act, _ctx| {
+            act.record_delivery(id, attempts, result);
+        }));
+    }
+}
+
+impl Handler<GetHistory> for AlertManager {
+    type Result = MessageResult<GetHistory>;
+
+    // Newest first
+    fn handle(&mut self, msg: GetHistory, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.history.iter().rev().take(msg.0).cloned().collect())
+    }
+}
+
+struct Alerts {
+    rules: RuleSet,
+    manager: Addr<AlertManager>,
+}
+
+pub fn init(node_id: u32, channel_manager: Addr<ChannelManager>) {
+    let rules = RuleSet::default();
+    let manager = AlertManager::new(node_id, rules.clone(), Some(channel_manager)).start();
+    if ALERTS.set(Alerts { rules, manager }).is_err() {
+        error!("Alerts already initialised");
+    }
+}
+
+// Called by the owner's LocationActor with every committed write
+pub fn evaluate(location_id: &str, stats: &EnrichedLocationStats) {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+    let rules = alerts.rules.read().unwrap_or_else(|err| err.into_inner());
+    for compiled in rules.active.values().filter(|compiled| compiled.matches(location_id, stats)) {
+        alerts.manager.do_send(Fire {
+            rule: compiled.rule.clone(),
+            value: compiled.predicate.field.value(stats),
+            location_id: location_id.to_owned(),
+            stats: stats.clone(),
+        });
+    }
+}
+
+fn alerts() -> Result<&'static Alerts, ShardError> {
+    ALERTS.get().ok_or_else(|| ShardError::Unavailable("alerts are not running".to_owned()))
+}
+
+// A rule deleted before its creation reached this node stays deleted
+pub fn put_rule(rule: AlertRule) -> Result<(), ShardError> {
+    let compiled = rule.compile()?;
+    alerts()?.rules.write().unwrap_or_else(|err| err.into_inner()).insert(compiled);
+    Ok(())
+}
+
+pub fn delete_rule(id: &str) -> Result<bool, ShardError> {
+    Ok(alerts()?.rules.write().unwrap_or_else(|err| err.into_inner()).remove(id))
+}
+
+pub fn rules() -> Result<Vec<AlertRule>, ShardError> {
+    Ok(alerts()?.rules.read().unwrap_or_else(|err| err.into_inner()).active.values().map(|c| c.rule.clone()).collect())
+}
+
+pub fn deleted_rule_ids() -> Result<Vec<String>, ShardError> {
+    Ok(alerts()?.rules.read().unwrap_or_else(|err| err.into_inner()).deleted.keys().cloned().collect())
+}
+
+pub async fn history(limit: usize) -> Result<Vec<AlertEvent>, ShardError> {
+    Ok(alerts()?.manager.send(GetHistory(limit)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use super::*;
+    use crate::test_support::{stats, stats_at};
+
+    fn rule(webhook_url: String, cooldown_secs: u64) -> AlertRule {
+        AlertRule::from_spec(AlertRuleSpec {
+            field: "radiation_level".to_owned(),
+            condition: "gt:0.5".to_owned(),
+            location_pattern: Some("site-a/*".to_owned()),
+            webhook_url,
+            cooldown_secs: Some(cooldown_secs),
+        }).unwrap()
+    }
+
+    // A local webhook that fails the first `failures` calls and records the rest
+    fn stub_webhook(failures: usize) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::new(Mutex::new(0usize));
+        let state = received.clone();
+        let server = HttpServer::new(move || {
+            let state = state.clone();
+            let calls = calls.clone();
+            App::new().route("/hook", web::post().to(move |body: web::Json<serde_json::Value>| {
+                let state = state.clone();
+                let calls = calls.clone();
+                async move {
+                    let mut calls = calls.lock().unwrap();
+                    *calls += 1;
+                    if *calls <= failures {
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                    state.lock().unwrap().push(body.into_inner());
+                    HttpResponse::Ok().finish()
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        actix_rt::spawn(server.run());
+        (format!("http://127.0.0.1:{}/hook", port), received)
+    }
+
+    async fn wait_for_status(manager: &Addr<AlertManager>, status: DeliveryStatus) -> AlertEvent {
+        for _ in 0..100 {
+            let history = manager.send(GetHistory(1)).await.unwrap();
+            if let Some(event) = history.into_iter().find(|event| event.status == status) {
+                return event;
+            }
+            actix_rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Alert never reached {:?}", status);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("site-a/*", "site-a/42"));
+        assert!(glob_match("*/sensor-*", "site-b/sensor-7"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exact-not"));
+        assert!(!glob_match("site-a/*", "site-b/42"));
+        assert!(!glob_match("a*a", "a"));
+    }
+
+    #[test]
+    fn test_rule_spec_is_validated() {
+        assert!(AlertRule::from_spec(AlertRuleSpec {
+            field: "humidity".to_owned(),
+            condition: "gt:1".to_owned(),
+            location_pattern: None,
+            webhook_url: "http://localhost/hook".to_owned(),
+            cooldown_secs: None,
+        }).is_err());
+        assert!(AlertRule::from_spec(AlertRuleSpec {
+            field: "radiation_level".to_owned(),
+            condition: "gt:1".to_owned(),
+            location_pattern: None,
+            webhook_url: "ftp://localhost/hook".to_owned(),
+            cooldown_secs: None,
+        }).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_fired_alert_is_delivered_after_retries() {
+        let (url, received) = stub_webhook(1);
+        let mut manager = AlertManager::new(1, RuleSet::default(), None);
+        manager.retry_backoff = Duration::from_millis(10);
+        let manager = manager.start();
+        let rule = rule(url, 60);
+
+        manager.send(Fire { rule: rule.clone(), value: 0.9, location_id: "site-a/1".to_owned(), stats: stats_at(3, 1.0, 20.0, 0.9) }).await.unwrap();
+        // Inside the cooldown, so no second delivery
+        manager.send(Fire { rule, value: 0.9, location_id: "site-a/1".to_owned(), stats: stats_at(3, 1.0, 20.0, 0.9) }).await.unwrap();
+
+        let event = wait_for_status(&manager, DeliveryStatus::Delivered).await;
+        assert_eq!(event.attempts, 2);
+        assert_eq!(manager.send(GetHistory(10)).await.unwrap().len(), 1);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["location_id"], "site-a/1");
+        assert_eq!(received[0]["stats"]["modification_count"], 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_delivery_gives_up_after_max_attempts() {
+        let (url, received) = stub_webhook(usize::MAX);
+        let mut manager = AlertManager::new(1, RuleSet::default(), None);
+        manager.retry_backoff = Duration::from_millis(10);
+        let manager = manager.start();
+
+        manager.send(Fire { rule: rule(url, 0), value: 0.9, location_id: "site-a/1".to_owned(), stats: stats_at(3, 1.0, 20.0, 0.9) }).await.unwrap();
+
+        let event = wait_for_status(&manager, DeliveryStatus::Failed).await;
+        assert_eq!(event.attempts, WEBHOOK_MAX_ATTEMPTS);
+        assert!(event.error.is_some());
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compiled_rule_matches_pattern_and_condition() {
+        let compiled = rule("http://localhost/hook".to_owned(), 60).compile().unwrap();
+        assert!(compiled.matches("site-a/1", &stats(1.0, 20.0, 0.9)));
+        assert!(!compiled.matches("site-a/1", &stats(1.0, 20.0, 0.1)));
+        assert!(!compiled.matches("site-b/1", &stats(1.0, 20.0, 0.9)));
+    }
+
+    #[test]
+    fn test_sync_merge_honours_deletions() {
+        let kept = rule("http://localhost/hook".to_owned(), 60);
+        let deleted = rule("http://localhost/hook".to_owned(), 60);
+        let mut rules = Rules::default();
+        assert!(rules.insert(deleted.compile().unwrap()));
+        assert!(rules.remove(&deleted.id));
+
+        // A peer that missed the deletion offers the rule back and learns nothing new from us
+        assert_eq!(rules.merge(vec![kept.clone(), deleted.clone()], vec![]), (1, 0));
+        assert_eq!(rules.active.keys().collect::<Vec<_>>(), vec![&kept.id]);
+
+        // A deletion we missed removes the rule, and merging it again changes nothing
+        assert_eq!(rules.merge(vec![], vec![kept.id.clone()]), (0, 1));
+        assert_eq!(rules.merge(vec![kept.clone()], vec![kept.id.clone()]), (0, 0));
+        assert!(rules.active.is_empty());
+    }
+
+    #[test]
+    fn test_merged_history_is_newest_first_across_nodes() {
+        let event = |node_id: u32, id: u64, fired_at_ms: u64| AlertEvent {
+            id,
+            node_id,
+            rule_id: "rule".to_owned(),
+            location_id: "site-a/1".to_owned(),
+            field: "radiation_level".to_owned(),
+            condition: "gt:0.5".to_owned(),
+            value: 0.9,
+            modification_count: 1,
+            fired_at_ms,
+            status: DeliveryStatus::Failed,
+            attempts: 3,
+            error: Some("timed out".to_owned()),
+        };
+        // A peer's events come back over the wire unchanged
+        let remote = AlertEvent::from_proto(event(2, 1, 300).to_proto());
+        assert_eq!(remote.status, DeliveryStatus::Failed);
+        assert_eq!(remote.error.as_deref(), Some("timed out"));
+
+        let merged = merge_history(vec![event(1, 1, 100), event(1, 2, 200), remote, event(3, 7, 50)], 3);
+        let order: Vec<_> = merged.iter().map(|event| (event.node_id, event.id)).collect();
+        assert_eq!(order, vec![(2, 1), (1, 2), (1, 1)]);
+    }
+}
@@ -1,241 +1,690 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::future::{self, Future};
+use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::sync::Arc;
 use std::time::Duration;
 use actix::{Actor, Addr, Arbiter, SyncArbiter};
-use actix_web::{put, web, App, HttpResponse, HttpServer};
+use actix_web::{delete, patch, post, put, web, App, HttpResponse, HttpServer};
+use actix_web::middleware::from_fn;
+use actix_web::http::{header, StatusCode};
 use actix_web::{get, HttpRequest, Responder};
 use actix_web::dev::Path;
 use actix_web::test::status_service;
-use actix_web::web::{Data, Json};
+use actix_web::web::{Bytes, Data, Json, JsonConfig};
 use awc::{Client, JsonBody};
-use futures::future::{try_join, try_join_all};
+use futures::future::{join, join_all, try_join};
+use futures::stream::{self, StreamExt};
 use futures::{FutureExt};
 use log::{error, info};
+use serde::Deserialize;
 use tokio::join;
+use tokio::net::TcpListener;
 use tonic::transport::{Channel, Server};
 use tonic::{IntoRequest, Request, Status};
-use crate::conn_manager::{ChannelManager, GetAllChannels, GetChannel, ResetChannel};
-use crate::constants::ROOT_ACTOR_POOL_SIZE;
-use crate::dto::{EnrichedLocationStats, ExtendedLocationStats, LocationStats, ShardError};
-use crate::location_actor::{GetLocation, GetShard, PutLocation};
-use crate::node::Node;
-use crate::root_actor::{GetAddr, RootActor};
+use crate::admin::AdminApi;
+use crate::aggregate::{self, AggregateSpec, GroupPartial, Partials};
+use crate::auth::{self, Auth};
+use crate::alerts::{self, AlertEvent, AlertHistory, AlertRule, AlertRuleChange, AlertRuleSpec};
+use crate::coordinator::{record_rpc_failure, Coordinator};
+use crate::conn_manager::{ChannelManager, GetAllChannels, GetChannel};
+use crate::constants::{DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT, ROOT_ACTOR_POOL_SIZE};
+use crate::dto::{etag, parse_if_match, IfMatch, AggregateReport, BatchGetRequest, BatchGetResult, BatchItemResult, BatchPutItem, LocationPage, LocationPatch, LocationStats, NodeReport, NodeStatus, QueryMatch, QueryPage, ShardError};
+use crate::feed::{self, sse_event, WatchFilter};
+use crate::health::{self, node_status, readiness};
+use crate::node::{LocationApi, Node};
+use crate::query::{self, Predicate};
+use crate::root_actor::{list_owned, RootActor};
+use crate::rpc::{self, RpcConfig};
 use crate::rs;
+use crate::rs::rs::admin_server::AdminServer;
+use crate::rs::rs::location_service_server::LocationServiceServer;
+use crate::rs::rs::rs_client::RsClient;
 use crate::rs::rs::rs_server::{Rs, RsServer};
-use crate::rs::rs::{GetShardRequest, GetShardResponse, RouteWriteRequest, WriteShardRequest};
-use crate::util::get_owner_node_id;
+use crate::rs::rs::{DeleteAlertRuleRequest, ListLocationsRequest, NodeStatusRequest, QueryLocationsRequest, WriteShardRequest};
+use crate::sender;
+use crate::shard_batcher;
+use crate::shutdown::{self, Drain};
+use crate::tls;
+use crate::trace::{self, Span, SpanKind, TraceContext};
+use crate::util::{decode_cursor, encode_cursor, get_owner_node_id};
+use crate::validation::{validate_location_id, ValidationConfig};
+
+const CLUSTER_STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+const HTTP_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_JSON_PAYLOAD: usize = 8 * 1024 * 1024;
+const SSE_HEARTBEAT: Duration = Duration::from_secs(15);
+
+
+// Asks a peer for its status over the node channel, as this node, so the caller's
+// credentials never leave it
+async fn fetch_node_status(channel_manager: &Addr<ChannelManager>, node_id: u32, endpoint: String) -> NodeReport {
+    let result = match channel_manager.send(GetChannel(node_id)).await {
+        Ok(Ok(channel)) => {
+            let mut request = Request::new(NodeStatusRequest {});
+            sender::stamp(&mut request);
+            request.set_timeout(CLUSTER_STATUS_TIMEOUT);
+            match tokio::time::timeout(CLUSTER_STATUS_TIMEOUT, RsClient::new(channel).node_status(request)).await {
+                Ok(Ok(res)) => serde_json::from_slice::<NodeStatus>(&res.into_inner().status).map_err(|err| err.to_string()),
+                Ok(Err(status)) => {
+                    record_rpc_failure(channel_manager, node_id, &status);
+                    Err(status.to_string())
+                }
+                Err(_) => Err(format!("no answer within {}ms", CLUSTER_STATUS_TIMEOUT.as_millis())),
+            }
+        }
+        Ok(Err(err)) => Err(err),
+        Err(err) => Err(err.to_string()),
+    };
 
+    if let Err(err) = &result {
+        error!("Failed to fetch cluster status from node {}: {}", node_id, err);
+    }
 
+    NodeReport {
+        node_id,
+        endpoint,
+        status: result.as_ref().ok().cloned(),
+        error: result.err(),
+    }
+}
 
-async fn read_shard_from_node(
-    addr: Arc<Addr<ChannelManager>>,
-    node_id: u32,
-    channel: Channel,
-    location_id: String,
-) -> Result<GetShardResponse, ShardError> {
-    let mut client = rs::rs::rs_client::RsClient::new(channel);
-    
-    let request = Request::new(GetShardRequest {
-        location_id
-    });
-    
-    match client.get_shard_request(request).await {
-        Ok(res) => Ok(res.into_inner()),
-        Err(status) => {
-            if is_connection_error(&status) {
-                addr.do_send(ResetChannel(node_id));
+
+// The condition of a conditional write, from its If-Match header
+fn if_match(req: &HttpRequest) -> Result<Option<IfMatch>, ShardError> {
+    match req.headers().get(header::IF_MATCH) {
+        Some(value) => {
+            let value = value.to_str().map_err(|err| ShardError::InvalidInput(err.to_string()))?;
+            parse_if_match(value).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/health")]
+async fn index(_req: HttpRequest) -> impl Responder {
+    "pong"
+}
+
+#[get("/ready")]
+async fn ready(root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>, current_node: Data<u32>, endpoints: Data<Vec<String>>, drain: Data<Drain>) -> impl Responder {
+    match node_status(**current_node, &root_actor_pool, &channel_manager, &endpoints).await {
+        Ok(status) => {
+            let readiness = readiness(&status, drain.is_draining());
+            if readiness.ready {
+                HttpResponse::Ok().json(readiness)
+            } else {
+                HttpResponse::ServiceUnavailable().json(readiness)
             }
-            
-            error!("Failed to read shard from node {}: {}", node_id, status);
-            Ok(GetShardResponse {
-                shard: None,
-                location_stats: None,
-            })
+        }
+        Err(err) => {
+            error!("Failed to collect node status: {}", err);
+            HttpResponse::ServiceUnavailable().json("Failed to collect node status")
         }
     }
 }
 
-fn is_connection_error(status: &Status) -> bool {
-    matches!(
-        status.code(),
-        tonic::Code::Unavailable | 
-        tonic::Code::DeadlineExceeded | 
-        tonic::Code::Cancelled |
-        tonic::Code::Aborted
-    )
+#[get("/cluster")]
+async fn cluster(root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>, current_node: Data<u32>, endpoints: Data<Vec<String>>) -> Result<HttpResponse, ShardError> {
+    let status = node_status(**current_node, &root_actor_pool, &channel_manager, &endpoints).await?;
+    Ok(HttpResponse::Ok().json(status))
 }
 
+#[get("/cluster/all")]
+async fn cluster_all(root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>, current_node: Data<u32>, endpoints: Data<Vec<String>>) -> impl Responder {
+    let current_node = **current_node;
+    let reports = join_all(endpoints.iter().cloned().enumerate().map(|(i, endpoint)| {
+        let node_id = i as u32;
+        let root_actor_pool = root_actor_pool.clone();
+        let channel_manager = channel_manager.clone();
+        let endpoints = endpoints.clone();
+        async move {
+            if node_id != current_node {
+                return fetch_node_status(&channel_manager, node_id, endpoint).await;
+            }
 
-#[get("/health")]
-async fn index(_req: HttpRequest) -> impl Responder {
-    "pong"
+            let result = node_status(current_node, &root_actor_pool, &channel_manager, &endpoints).await;
+            NodeReport {
+                node_id,
+                endpoint,
+                error: result.as_ref().err().map(|err| err.to_string()),
+                status: result.ok(),
+            }
+        }
+    })).await;
+
+    HttpResponse::Ok().json(reports)
 }
 
 #[put("/{location_id}")]
-async fn put(body: Json<LocationStats>, id: web::Path<String>, root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>, current_node: Data<u32>, endpoints: Data<Vec<String>>, client: Data<Client>) -> impl Responder {
+async fn put(req: HttpRequest, body: Json<LocationStats>, id: web::Path<String>, coordinator: Data<Coordinator>, drain: Data<Drain>) -> Result<HttpResponse, ShardError> {
+    let Some(_in_flight) = drain.enter() else {
+        return Err(ShardError::Unavailable("node is shutting down".to_owned()));
+    };
     let location_id = id.into_inner();
-    let root_actor = root_actor_pool.get((get_owner_node_id(location_id.clone()) % ROOT_ACTOR_POOL_SIZE) as usize).unwrap();
-    let owner_id = get_owner_node_id(location_id.clone());
- 
-
-    if *current_node.into_inner() != owner_id {
-        let channel = channel_manager
-        .send(GetChannel(owner_id))
-        .await.unwrap().unwrap();
-        let mut client = rs::rs::rs_client::RsClient::new(channel.clone());
-
-        match client.route_write(Request::new(RouteWriteRequest {
-            location_id: location_id.clone(),
-            id: body.id.clone(),
-            seismic_activity: body.seismic_activity,
-            temperature_c: body.temperature_c,
-            radiation_level: body.radiation_level
-        })).await {
-            Ok(_) => {
-                return HttpResponse::Created().json(());
-            }
-            Err(status) => {
-                if is_connection_error(&status) {
-                    channel_manager.do_send(ResetChannel(owner_id));
-                }
-                error!("Failed to route write to node {}: {}", owner_id, status);
-                return HttpResponse::InternalServerError().json("Failed to route write");
-            }  
-            
+    let expected = if_match(&req)?;
+    let mut span = Span::start("http.put", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("location_id", &location_id);
+    span.set_attribute("owner.node_id", get_owner_node_id(location_id.clone()));
+
+    match coordinator.put(location_id.clone(), body.into_inner(), expected, span.context()).await {
+        Ok(committed) => Ok(HttpResponse::Created()
+            .insert_header((header::ETAG, etag(committed.modification_count)))
+            .json(())),
+        Err(err) => {
+            span.set_error(&err);
+            error!("Failed to put location stats: {} {}", err, location_id);
+            Err(err)
         }
+    }
+}
 
-        // let url = format!("http://{}/{}", endpoints.get(owner_id as usize).unwrap(),location_id);
-        // let resp = client
-        // .put(&url).send_json(&body.clone()).await;
+#[patch("/{location_id}")]
+async fn patch(req: HttpRequest, body: Json<LocationPatch>, id: web::Path<String>, coordinator: Data<Coordinator>, drain: Data<Drain>) -> Result<HttpResponse, ShardError> {
+    let Some(_in_flight) = drain.enter() else {
+        return Err(ShardError::Unavailable("node is shutting down".to_owned()));
+    };
+    let location_id = id.into_inner();
+    let expected = if_match(&req)?;
+    let mut span = Span::start("http.patch", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("location_id", &location_id);
+    span.set_attribute("owner.node_id", get_owner_node_id(location_id.clone()));
+
+    match coordinator.patch(location_id.clone(), body.into_inner(), expected, span.context()).await {
+        Ok(committed) => Ok(HttpResponse::Ok()
+            .insert_header((header::ETAG, etag(committed.modification_count)))
+            .json(committed)),
+        Err(err) => {
+            span.set_error(&err);
+            error!("Failed to patch location stats: {} {}", err, location_id);
+            Err(err)
+        }
+    }
+}
 
-        // // info!("Sending location stats to {}: {:?}", url, body.id);
+#[delete("/{location_id}")]
+async fn delete_location(req: HttpRequest, id: web::Path<String>, coordinator: Data<Coordinator>, drain: Data<Drain>) -> Result<HttpResponse, ShardError> {
+    let Some(_in_flight) = drain.enter() else {
+        return Err(ShardError::Unavailable("node is shutting down".to_owned()));
+    };
+    let location_id = id.into_inner();
+    let mut span = Span::start("http.delete", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("location_id", &location_id);
+
+    match coordinator.delete(location_id.clone(), span.context()).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(err) => {
+            span.set_error(&err);
+            error!("Failed to delete location: {} {}", err, location_id);
+            Err(err)
+        }
+    }
+}
 
-        // if let Err(err) = resp {
-        //     error!("Failed to put location stats: {} {}", err, location_id);
-        //     return HttpResponse::InternalServerError().json("Failed to put location stats");
-        // }
+#[post("/batch")]
+async fn batch_put(req: HttpRequest, body: Json<Vec<BatchPutItem>>, coordinator: Data<Coordinator>, drain: Data<Drain>) -> Result<HttpResponse, ShardError> {
+    let Some(_in_flight) = drain.enter() else {
+        return Err(ShardError::Unavailable("node is shutting down".to_owned()));
+    };
+    let items = body.into_inner();
+    let mut span = Span::start("http.batch_put", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("batch.size", items.len());
+
+    let results: Vec<BatchItemResult> = coordinator.put_batch(items, span.context()).await?
+        .into_iter()
+        .map(|(location_id, result)| BatchItemResult::from_result(location_id, StatusCode::CREATED, result))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(results))
+}
 
-        // let result=  resp.unwrap();
-        // if result.status() != 201 {
-        //     error!("Failed to put location stats: {} {}", result.status(), location_id);
-        //     return HttpResponse::InternalServerError().json("Failed to put location stats");
-        // }
-    
-        // return HttpResponse::Created().json(());
+#[post("/batch/get")]
+async fn batch_get(req: HttpRequest, body: Json<BatchGetRequest>, coordinator: Data<Coordinator>) -> Result<HttpResponse, ShardError> {
+    let location_ids = body.into_inner().location_ids;
+    let mut span = Span::start("http.batch_get", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("batch.size", location_ids.len());
+
+    let results: BTreeMap<String, BatchGetResult> = coordinator.get_batch(location_ids, span.context()).await?
+        .into_iter()
+        .map(|(location_id, result)| (location_id, BatchGetResult::from_result(result)))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/locations")]
+async fn list_locations(req: HttpRequest, query: web::Query<ListQuery>, root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>) -> Result<HttpResponse, ShardError> {
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_LIST_LIMIT);
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+        return Err(ShardError::InvalidInput(format!("limit must be between 1 and {}", MAX_LIST_LIMIT)));
+    }
+    let after = match query.cursor.filter(|cursor| !cursor.is_empty()) {
+        Some(cursor) => Some(decode_cursor(&cursor).ok_or_else(|| ShardError::InvalidInput("Invalid cursor".to_owned()))?),
+        None => None,
+    };
+
+    let mut span = Span::start("http.list_locations", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    let trace = span.context();
+    let local = list_owned(&root_actor_pool, after.clone(), limit);
+    let page = gather_pages(&channel_manager, "list locations", local, limit, |id: &String| id.as_str(), |mut client| {
+        let mut request = Request::new(ListLocationsRequest { after: after.clone().unwrap_or_default(), limit: limit as u32 });
+        trace.inject(&mut request);
+        request.set_timeout(CLUSTER_STATUS_TIMEOUT);
+        async move { client.list_locations(request).await.map(|res| res.into_inner().location_ids) }
+    }).await?;
+
+    span.set_attribute("page.size", page.items.len());
+    if !page.unavailable_nodes.is_empty() {
+        span.set_error("some nodes could not be listed");
     }
 
+    let next_cursor = if page.has_more { page.items.last().map(|id| encode_cursor(id)) } else { None };
+    Ok(HttpResponse::Ok().json(LocationPage { location_ids: page.items, next_cursor, unavailable_nodes: page.unavailable_nodes }))
+}
+
+// One merged page out of this node's own page and every peer's
+struct GatheredPage<T> {
+    items: Vec<T>,
+    // A source that filled its page may hold more past this one
+    has_more: bool,
+    unavailable_nodes: Vec<u32>,
+}
 
-   let addr =  root_actor.send(GetAddr(location_id.clone())).await.unwrap().unwrap();
-    if let Err(err) = addr.send(PutLocation(ExtendedLocationStats::from_basic(location_id.clone(), body.into_inner()), channel_manager.into_inner())).await.unwrap() {
-        error!("Failed to put location stats: {} {}", err, location_id);
-        return HttpResponse::InternalServerError().json("Failed to put location stats");
+// Asks every peer for a page with `call` while `local` reads this node's, then merges
+// the pages by `key`, dropping duplicates. Peers that fail are reported, not fatal.
+async fn gather_pages<T, K, F, Fut>(
+    channel_manager: &Addr<ChannelManager>,
+    what: &str,
+    local: impl Future<Output = Result<Vec<T>, ShardError>>,
+    limit: usize,
+    key: K,
+    call: F,
+) -> Result<GatheredPage<T>, ShardError>
+where
+    K: Fn(&T) -> &str,
+    F: Fn(RsClient<Channel>) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, Status>>,
+{
+    let (current_node, channels) = channel_manager
+        .send(GetAllChannels {})
+        .await?
+        .map_err(|_| ShardError::ChannelError("Failed to list channels".to_owned()))?;
+
+    let remote = channels.into_iter()
+        .filter(|(node_id, _)| *node_id != current_node)
+        .map(|(node_id, channel)| call(RsClient::new(channel)).map(move |result| {
+            if let Err(status) = &result {
+                record_rpc_failure(&channel_manager, node_id, status);
+                error!("Failed to {} on node {}: {}", what, node_id, status);
+            }
+            (node_id, result)
+        }));
+    let (local, remote) = join(local, join_all(remote)).await;
+
+    let mut has_more = local.as_ref().map_or(false, |items| items.len() == limit);
+    let mut items = local?;
+    let mut unavailable_nodes = Vec::new();
+    for (node_id, result) in remote {
+        match result {
+            Ok(page) => {
+                has_more |= page.len() == limit;
+                items.extend(page);
+            }
+            Err(_) => unavailable_nodes.push(node_id),
+        }
     }
+    items.sort_by(|a, b| key(a).cmp(key(b)));
+    items.dedup_by(|a, b| key(a) == key(b));
+    has_more |= items.len() > limit;
+    items.truncate(limit);
+    unavailable_nodes.sort();
 
-    return HttpResponse::Created().json(());
+    Ok(GatheredPage { items, has_more, unavailable_nodes })
 }
 
-#[get("/{location_id}")]
-async fn get(id: web::Path<(String)>, root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>) -> impl Responder {
-    let (location_id) = id.into_inner();
-    let root_actor = root_actor_pool.get((get_owner_node_id(location_id.clone()) % ROOT_ACTOR_POOL_SIZE) as usize).unwrap();
-    let addr =  root_actor.send(GetAddr(location_id.clone())).await.unwrap().unwrap();
-    if let Ok(location_stats) = addr.send(GetLocation).await.unwrap() {
-        return HttpResponse::Ok().json(location_stats);
+// GET /query?radiation_level=gt:5&seismic_activity=between:1,2&limit=&cursor=
+#[get("/query")]
+async fn query_locations(req: HttpRequest, params: web::Query<HashMap<String, String>>, root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>) -> Result<HttpResponse, ShardError> {
+    let mut params = params.into_inner();
+    let limit = match params.remove("limit") {
+        Some(limit) => limit.parse().map_err(|_| ShardError::InvalidInput(format!("Invalid limit {}", limit)))?,
+        None => DEFAULT_LIST_LIMIT,
+    };
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+        return Err(ShardError::InvalidInput(format!("limit must be between 1 and {}", MAX_LIST_LIMIT)));
     }
+    let after = match params.remove("cursor").filter(|cursor| !cursor.is_empty()) {
+        Some(cursor) => Some(decode_cursor(&cursor).ok_or_else(|| ShardError::InvalidInput("Invalid cursor".to_owned()))?),
+        None => None,
+    };
+    let mut predicates = params.iter()
+        .map(|(field, expr)| Predicate::parse(field, expr))
+        .collect::<Result<Vec<_>, _>>()?;
+    predicates.sort_by_key(|predicate| predicate.field.name());
+
+    let mut span = Span::start("http.query", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("query.predicates", predicates.len());
+    let trace = span.context();
+    let local = query::scan(&root_actor_pool, &predicates, after.clone(), limit);
+    let page = gather_pages(&channel_manager, "query locations", local, limit, |m: &QueryMatch| m.location_id.as_str(), |mut client| {
+        let mut request = Request::new(QueryLocationsRequest {
+            predicates: predicates.iter().map(|predicate| predicate.to_proto()).collect(),
+            after: after.clone().unwrap_or_default(),
+            limit: limit as u32,
+        });
+        trace.inject(&mut request);
+        async move {
+            let matches = client.query_locations(request).await?.into_inner().matches;
+            matches.into_iter().map(QueryMatch::from_proto).collect::<Result<Vec<_>, _>>().map_err(Status::from)
+        }
+    }).await?;
 
-    if let Ok(shard) = addr.send(GetShard(location_id.clone())).await.unwrap() {
-        let channels_result = channel_manager
-        .send(GetAllChannels {})
-        .await;
+    span.set_attribute("query.matches", page.items.len());
+    if !page.unavailable_nodes.is_empty() {
+        span.set_error("some nodes could not be queried");
+    }
 
-        let (_, channels) = channels_result.unwrap().unwrap();
+    let next_cursor = if page.has_more { page.items.last().map(|m| encode_cursor(&m.location_id)) } else { None };
+    Ok(HttpResponse::Ok().json(QueryPage { matches: page.items, next_cursor, unavailable_nodes: page.unavailable_nodes }))
+}
 
-        let mut f = Vec::new();
+#[derive(Deserialize)]
+struct AggregateQuery {
+    prefix: Option<String>,
+    group_prefix_len: Option<usize>,
+}
 
-        for i in 0..7 {
-                f.push(read_shard_from_node(channel_manager.clone().into_inner(), i, channels.get(&i).unwrap().clone(), location_id.clone()));
-        }
-        let res = try_join_all(f).boxed().await.unwrap();
-        
-        let mut original_shards: HashMap<usize, Vec<u8>> = HashMap::new();
-        let mut recovery_shards: HashMap<usize, Vec<u8>> = HashMap::new();
-
-        let owner_node_id = get_owner_node_id(location_id);
-
-        if let Some(location_stats) = res[owner_node_id as usize].location_stats.clone() {
-            let enriched_location_stats = EnrichedLocationStats {
-                id: location_stats.id,
-                seismic_activity: location_stats.seismic_activity,
-                temperature_c: location_stats.temperature_c,
-                radiation_level: location_stats.radiation_level,
-                modification_count: location_stats.modification_count,
-            };
-            
-            return HttpResponse::Ok().json(enriched_location_stats);
+// GET /aggregate?prefix=site-&group_prefix_len=6
+#[get("/aggregate")]
+async fn aggregate_locations(req: HttpRequest, query: web::Query<AggregateQuery>, root_actor_pool: Data<Vec<Addr<RootActor>>>, channel_manager: Data<Addr<ChannelManager>>, validation: Data<ValidationConfig>) -> Result<HttpResponse, ShardError> {
+    let query = query.into_inner();
+    let spec = AggregateSpec {
+        prefix: query.prefix.filter(|prefix| !prefix.is_empty()),
+        group_prefix_len: query.group_prefix_len.unwrap_or(0),
+    };
+    if spec.group_prefix_len > validation.max_location_id_len {
+        return Err(ShardError::InvalidInput(format!("group_prefix_len must be at most {}", validation.max_location_id_len)));
+    }
+
+    let mut span = Span::start("http.aggregate", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    let (current_node, channels) = channel_manager
+        .send(GetAllChannels {})
+        .await?
+        .map_err(|_| ShardError::ChannelError("Failed to list channels".to_owned()))?;
+
+    let remote = channels.into_iter()
+        .filter(|(node_id, _)| *node_id != current_node)
+        .map(|(node_id, channel)| {
+            let mut request = Request::new(spec.to_proto());
+            span.context().inject(&mut request);
+            let channel_manager = channel_manager.clone();
+            async move {
+                let mut client = rs::rs::rs_client::RsClient::new(channel);
+                let result = client.aggregate(request).await
+                    .map(|res| res.into_inner().groups.into_iter().map(GroupPartial::from_proto).collect::<Partials>());
+                if let Err(status) = &result {
+                    record_rpc_failure(&channel_manager, node_id, status);
+                    error!("Failed to aggregate on node {}: {}", node_id, status);
+                }
+                (node_id, result)
+            }
+        });
+    let (local, remote) = join(aggregate::aggregate(&root_actor_pool, &spec), join_all(remote)).await;
+
+    let mut partials = local?;
+    let mut unavailable_nodes = Vec::new();
+    for (node_id, result) in remote {
+        match result {
+            Ok(other) => aggregate::merge(&mut partials, other),
+            Err(_) => unavailable_nodes.push(node_id),
         }
+    }
+    unavailable_nodes.sort();
 
-        let mut shard_count = 0;
+    span.set_attribute("aggregate.groups", partials.len());
+    if !unavailable_nodes.is_empty() {
+        span.set_error("some nodes could not be aggregated");
+    }
 
+    let mut total = GroupPartial::default();
+    for partial in partials.values() {
+        total.merge(partial.clone());
+    }
+    let groups = (spec.group_prefix_len > 0).then(|| {
+        partials.into_iter().map(|(key, partial)| (key, partial.summary())).collect()
+    });
 
-        for i in 0..6usize {
-            let node_id = if i as u32 >= owner_node_id {
-                i+1
-            } else {
-                i
-            };
-            if let Some(shard) = res[node_id as usize].shard.clone() {
-                shard_count += 1;
-                if i >= 4 {
-                    recovery_shards.insert(i-4, shard);
-                } else {
-                    original_shards.insert(i, shard);
+    Ok(HttpResponse::Ok().json(AggregateReport { total: total.summary(), groups, unavailable_nodes }))
+}
+
+enum RuleChange {
+    Put(AlertRule),
+    Delete(String),
+}
+
+// Applies an alert rule change on every peer, returning the nodes that missed it
+async fn broadcast_rule_change(channel_manager: &Addr<ChannelManager>, change: RuleChange, trace: TraceContext) -> Result<Vec<u32>, ShardError> {
+    let (current_node, channels) = channel_manager
+        .send(GetAllChannels {})
+        .await?
+        .map_err(|_| ShardError::ChannelError("Failed to list channels".to_owned()))?;
+
+    let calls = channels.into_iter()
+        .filter(|(node_id, _)| *node_id != current_node)
+        .map(|(node_id, channel)| {
+            let mut client = rs::rs::rs_client::RsClient::new(channel);
+            let trace = trace.clone();
+            let change = &change;
+            async move {
+                let result = match change {
+                    RuleChange::Put(rule) => {
+                        let mut request = Request::new(rule.to_proto());
+                        trace.inject(&mut request);
+                        client.put_alert_rule(request).await.map(|_| ())
+                    }
+                    RuleChange::Delete(id) => {
+                        let mut request = Request::new(DeleteAlertRuleRequest { id: id.clone() });
+                        trace.inject(&mut request);
+                        client.delete_alert_rule(request).await.map(|_| ())
+                    }
+                };
+                if let Err(status) = &result {
+                    record_rpc_failure(&channel_manager, node_id, status);
+                    error!("Failed to replicate alert rule change to node {}: {}", node_id, status);
                 }
-          
+                (node_id, result.is_ok())
             }
-        }
+        });
+
+    let mut missed: Vec<u32> = join_all(calls).await.into_iter()
+        .filter(|(_, ok)| !ok)
+        .map(|(node_id, _)| node_id)
+        .collect();
+    missed.sort();
+    Ok(missed)
+}
 
-        if shard_count < 4 {
-            return HttpResponse::InternalServerError().json("Not enough shards");
-        }
+#[get("/alerts/rules")]
+async fn list_alert_rules() -> Result<HttpResponse, ShardError> {
+    Ok(HttpResponse::Ok().json(alerts::rules()?))
+}
 
-        let restored = reed_solomon_simd::decode(
-            4, 2, original_shards.iter()
-            .map(|(k, v)| (*k, v.clone())), 
-            recovery_shards
-            .iter()
-            .map(|(k, v)| (*k, v.clone()))
-        ).unwrap();
-
-        let mut shards: [Vec<u8>; 4] = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
-        for i in 0..4 {
-            if let Some(data) = original_shards.get(&i) {
-                shards[i] = data.clone();
-            } else if let Some(data) = restored.get(&i) {
-                shards[i] = data.clone();
-            } else {
-                error!("Failed to restore shard {}", i);
-                return HttpResponse::InternalServerError().json("Failed to restore shard");
+#[post("/alerts/rules")]
+async fn create_alert_rule(req: HttpRequest, body: Json<AlertRuleSpec>, channel_manager: Data<Addr<ChannelManager>>) -> Result<HttpResponse, ShardError> {
+    let span = Span::start("http.create_alert_rule", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    let rule = AlertRule::from_spec(body.into_inner())?;
+    alerts::put_rule(rule.clone())?;
+    let unavailable_nodes = broadcast_rule_change(&channel_manager, RuleChange::Put(rule.clone()), span.context()).await?;
+    Ok(HttpResponse::Created().json(AlertRuleChange { rule: Some(rule), unavailable_nodes }))
+}
+
+#[delete("/alerts/rules/{id}")]
+async fn delete_alert_rule(req: HttpRequest, id: web::Path<String>, channel_manager: Data<Addr<ChannelManager>>) -> Result<HttpResponse, ShardError> {
+    let span = Span::start("http.delete_alert_rule", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    let id = id.into_inner();
+    let deleted = alerts::delete_rule(&id)?;
+    let unavailable_nodes = broadcast_rule_change(&channel_manager, RuleChange::Delete(id.clone()), span.context()).await?;
+    if !deleted && unavailable_nodes.is_empty() {
+        return Err(ShardError::NotFoundError(format!("Alert rule {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().json(AlertRuleChange { rule: None, unavailable_nodes }))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+// Alerts fired across the cluster, newest first
+#[get("/alerts/history")]
+async fn alert_history(req: HttpRequest, query: web::Query<HistoryQuery>, channel_manager: Data<Addr<ChannelManager>>) -> Result<HttpResponse, ShardError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIST_LIMIT).min(MAX_LIST_LIMIT);
+
+    let mut span = Span::start("http.alert_history", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    let (current_node, channels) = channel_manager
+        .send(GetAllChannels {})
+        .await?
+        .map_err(|_| ShardError::ChannelError("Failed to list channels".to_owned()))?;
+
+    let remote = channels.into_iter()
+        .filter(|(node_id, _)| *node_id != current_node)
+        .map(|(node_id, channel)| {
+            let mut request = Request::new(rs::rs::AlertHistoryRequest { limit: limit as u32 });
+            span.context().inject(&mut request);
+            let channel_manager = channel_manager.clone();
+            async move {
+                let mut client = rs::rs::rs_client::RsClient::new(channel);
+                let result = client.alert_history(request).await
+                    .map(|res| res.into_inner().events.into_iter().map(AlertEvent::from_proto).collect::<Vec<_>>());
+                if let Err(status) = &result {
+                    record_rpc_failure(&channel_manager, node_id, status);
+                    error!("Failed to fetch alert history from node {}: {}", node_id, status);
+                }
+                (node_id, result)
             }
+        });
+    let (local, remote) = join(alerts::history(limit), join_all(remote)).await;
+
+    let mut events = local?;
+    let mut unavailable_nodes = Vec::new();
+    for (node_id, result) in remote {
+        match result {
+            Ok(other) => events.extend(other),
+            Err(_) => unavailable_nodes.push(node_id),
         }
+    }
+    unavailable_nodes.sort();
+    if !unavailable_nodes.is_empty() {
+        span.set_error("some nodes' alert history could not be fetched");
+    }
+
+    Ok(HttpResponse::Ok().json(AlertHistory { events: alerts::merge_history(events, limit), unavailable_nodes }))
+}
 
-        return EnrichedLocationStats::from_shards(shards)
-            .map(|enriched_location_stats| {
-                HttpResponse::Ok().json(enriched_location_stats)
-            })
-            .unwrap_or_else(|_| {
-                error!("Failed to decode shards");
-                HttpResponse::InternalServerError().json("Failed to decode shards")
-            });
+#[derive(Deserialize)]
+struct WatchQuery {
+    // Comma separated location ids and prefixes
+    ids: Option<String>,
+    prefix: Option<String>,
+}
+
+fn split_list(value: Option<String>) -> Vec<String> {
+    value.unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[get("/watch")]
+async fn watch(req: HttpRequest, query: web::Query<WatchQuery>, coordinator: Data<Coordinator>) -> Result<HttpResponse, ShardError> {
+    let query = query.into_inner();
+    let filter = WatchFilter {
+        location_ids: split_list(query.ids).into_iter().collect(),
+        prefixes: split_list(query.prefix),
+        ..WatchFilter::default()
+    };
+    if filter.is_empty() {
+        return Err(ShardError::InvalidInput("Watch needs ids or a prefix".to_owned()));
+    }
+    for location_id in &filter.location_ids {
+        validate_location_id(location_id, &coordinator.validation)?;
     }
 
+    let mut span = Span::start("http.watch", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_attribute("watch.locations", filter.location_ids.len());
+    let changes = coordinator.watch(filter, span.context()).await.map_err(|err| {
+        span.set_error(&err);
+        err
+    })?;
+    // The stream ends after its first error, which goes out as an error event
+    let changes = changes.map(|change| match change {
+        Ok(change) => sse_event("change", &change),
+        Err(err) => sse_event("error", &err.body()),
+    });
 
-    HttpResponse::NotFound().json(())
+    // Comment lines keep idle connections open through proxies
+    let events = stream::unfold(changes, |mut events| async move {
+        match tokio::time::timeout(SSE_HEARTBEAT, events.next()).await {
+            Ok(Some(event)) => Some((Ok::<_, actix_web::Error>(event), events)),
+            Ok(None) => None,
+            Err(_) => Some((Ok(Bytes::from_static(b": keepalive\n\n")), events)),
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events))
+}
+
+#[get("/{location_id}")]
+async fn get(req: HttpRequest, id: web::Path<String>, coordinator: Data<Coordinator>) -> Result<HttpResponse, ShardError> {
+    let location_id = id.into_inner();
+    let mut span = Span::start("http.get", TraceContext::from_http(&req).as_ref(), SpanKind::Server);
+    span.set_deadline(rpc::http_deadline(&req));
+    span.set_attribute("location_id", &location_id);
+
+    match coordinator.get(location_id, span.context()).await {
+        Ok(location_stats) => Ok(HttpResponse::Ok()
+            .insert_header((header::ETAG, etag(location_stats.modification_count)))
+            .json(location_stats)),
+        Err(err) => {
+            span.set_error(&err);
+            error!("Failed to read location: {}", err);
+            Err(err)
+        }
+    }
 }
 
 
 pub async fn bootstrap(current_node: u32,endpoint: Vec<String>, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    trace::init(current_node);
+    feed::init();
+    sender::init(current_node);
+    rpc::init(RpcConfig::from_env());
+    let tls_enabled = tls::init(&endpoint)?;
+    if tls_enabled {
+        actix_rt::spawn(tls::watch());
+    }
+
     let mut root_actor_pool: Vec<Addr<RootActor>> = Vec::new();
     for _ in 0..ROOT_ACTOR_POOL_SIZE {
         root_actor_pool.push(SyncArbiter::start(1, move || {
@@ -253,36 +702,106 @@ pub async fn bootstrap(current_node: u32,endpoint: Vec<String>, port: u16) -> Re
     let cm = ChannelManager::new(current_node, endpoints_hm.clone(), Duration::from_millis(300)).start();
     
 
-    let node = Node { root_actor: root_actor_pool, channel_manager: Arc::new(cm.clone()) };
+    let drain = Drain::new();
+    let validation = ValidationConfig::from_env();
+    let node = Node { current_node, root_actor: root_actor_pool.clone(), channel_manager: Arc::new(cm.clone()), drain: drain.clone(), validation: validation.clone(), endpoints: endpoint.clone() };
+    let coordinator = Coordinator { current_node, root_actor: root_actor_pool.clone(), channel_manager: Arc::new(cm.clone()), validation: validation.clone() };
+    let location_api = LocationApi { coordinator: coordinator.clone(), drain: drain.clone() };
+    let admin_api = AdminApi { current_node, root_actor: root_actor_pool.clone(), channel_manager: Arc::new(cm.clone()), validation: validation.clone() };
 
 
+    alerts::init(current_node, cm.clone());
+    shard_batcher::init(cm.clone());
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    actix_rt::spawn(health::report(health_reporter, current_node, root_actor_pool, cm.clone(), endpoint.clone(), drain.clone()));
     let channel_manager = Data::new(cm);
 
     let endpoint_clone = Data::new(endpoint.clone());
+    let drain_data = Data::new(drain.clone());
+    let validation_data = Data::new(validation);
+    let coordinator = Data::new(coordinator);
+    let auth = Auth::from_env()?;
+    let location_service = LocationServiceServer::with_interceptor(location_api, auth.clone());
+    let auth = Data::new(auth);
 
     let http_server = HttpServer::new(move || App::new()
+    .app_data(JsonConfig::default().limit(MAX_JSON_PAYLOAD).error_handler(|err, _req| ShardError::InvalidInput(err.to_string()).into()))
     .app_data(Data::clone(&root_actor))
     .app_data(Data::clone(&channel_manager))
     .app_data(Data::new(current_node))
     .app_data(Data::clone(&endpoint_clone))
     .app_data(Data::new(Client::default()))
+    .app_data(Data::clone(&drain_data))
+    .app_data(Data::clone(&validation_data))
+    .app_data(Data::clone(&coordinator))
+    .app_data(Data::clone(&auth))
+    .wrap(from_fn(auth::authenticate))
     .service(index)
+    .service(ready)
+    .service(cluster)
+    .service(cluster_all)
+    .service(batch_put)
+    .service(batch_get)
+    .service(list_locations)
+    .service(query_locations)
+    .service(aggregate_locations)
+    .service(list_alert_rules)
+    .service(create_alert_rule)
+    .service(delete_alert_rule)
+    .service(alert_history)
+    .service(watch)
     .service(put)
+    .service(patch)
+    .service(delete_location)
     .service(get))
+    .disable_signals()
+    .shutdown_timeout(HTTP_SHUTDOWN_TIMEOUT_SECS)
     .bind(("0.0.0.0", port)).unwrap()
     .run();
+    let http_handle = http_server.handle();
+    let (grpc_stop_tx, grpc_stop_rx) = tokio::sync::oneshot::channel::<()>();
 
     let reflection_service = tonic_reflection::server::Builder::configure()
     .register_encoded_file_descriptor_set(rs::rs::FILE_DESCRIPTOR_SET)
     .build_v1()
     .unwrap();
 
-    let grpc = Server::builder()
+    let grpc_router = Server::builder()
     .add_service(reflection_service)
-    .add_service(RsServer::new(node))
-    .serve(format!("0.0.0.0:{}", port+80).parse().unwrap());
-    
-    join!(grpc, http_server);
+    .add_service(health_service)
+    .add_service(RsServer::with_interceptor(node, tls::require_node))
+    .add_service(location_service)
+    .add_service(AdminServer::with_interceptor(admin_api, tls::require_node));
+    let grpc_addr: SocketAddr = format!("0.0.0.0:{}", port+80).parse().unwrap();
+    let grpc_shutdown = async move {
+        grpc_stop_rx.await.ok();
+    };
+    let grpc = if tls_enabled {
+        let listener = TcpListener::bind(grpc_addr).await?;
+        grpc_router.serve_with_incoming_shutdown(tls::incoming(listener), grpc_shutdown).left_future()
+    } else {
+        grpc_router.serve_with_shutdown(grpc_addr, grpc_shutdown).right_future()
+    };
+
+    // Stop taking client requests first, keep serving shard RPCs for peers until
+    // our own fan-outs are done, then stop gRPC and flush buffered spans
+    let shutdown = async move {
+        shutdown::signal().await;
+        info!("Shutting down, {} writes in flight", drain.in_flight());
+        drain.start();
+        feed::close().await;
+        http_handle.stop(true).await;
+
+        if !drain.wait_idle(DRAIN_TIMEOUT).await {
+            error!("Timed out draining writes, {} still in flight", drain.in_flight());
+        }
+
+        grpc_stop_tx.send(()).ok();
+        trace::flush().await;
+    };
+
+    join!(grpc, http_server, shutdown);
+    info!("Shutdown complete");
 
     Ok(())
 }
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpMessage};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde::Deserialize;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use crate::dto::ShardError;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+// Probes must keep working without credentials
+const PUBLIC_PATHS: [&str; 2] = ["/health", "/ready"];
+// POSTs that only read
+const READ_ONLY_POSTS: [&str; 1] = ["/batch/get"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl Permission {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+        }
+    }
+}
+
+// Who made a request, available to handlers as a request extension
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub subject: String,
+    pub permissions: HashSet<Permission>,
+}
+
+impl Principal {
+    // Stands in for the caller when authentication is off
+    fn anonymous() -> Self {
+        Principal { subject: "anonymous".to_owned(), permissions: HashSet::from([Permission::Read, Permission::Write]) }
+    }
+
+    fn require(&self, permission: Permission, what: &str) -> Result<(), ShardError> {
+        if self.permissions.contains(&permission) {
+            return Ok(());
+        }
+        warn!("{} lacks {} permission for {}", self.subject, permission.as_str(), what);
+        Err(ShardError::PermissionDenied(format!("{} permission required", permission.as_str())))
+    }
+}
+
+pub trait Authenticator: Send + Sync {
+    // None when the request carries no credentials of this kind
+    fn authenticate(&self, headers: &HeaderMap) -> Option<Result<Principal, ShardError>>;
+}
+
+fn unauthenticated(message: &str) -> ShardError {
+    ShardError::Unauthenticated(message.to_owned())
+}
+
+// Static keys from a file with one `<name> <key> <permissions>` line per key,
+// permissions being a comma-separated subset of read,write
+pub struct ApiKeys {
+    keys: HashMap<String, Principal>,
+}
+
+impl ApiKeys {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, key, permissions] = fields[..] else {
+                return Err(format!("line {}: expected `<name> <key> <permissions>`", i + 1));
+            };
+            let permissions = permissions.split(',')
+                .map(|permission| Permission::parse(permission).ok_or_else(|| format!("line {}: unknown permission '{}'", i + 1, permission)))
+                .collect::<Result<HashSet<_>, _>>()?;
+            if keys.insert(key.to_owned(), Principal { subject: name.to_owned(), permissions }).is_some() {
+                return Err(format!("line {}: duplicate key", i + 1));
+            }
+        }
+        Ok(ApiKeys { keys })
+    }
+}
+
+impl Authenticator for ApiKeys {
+    fn authenticate(&self, headers: &HeaderMap) -> Option<Result<Principal, ShardError>> {
+        let key = headers.get(API_KEY_HEADER)?;
+        Some(key.to_str().ok()
+            .and_then(|key| self.keys.get(key.trim()))
+            .cloned()
+            .ok_or_else(|| unauthenticated("invalid API key")))
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    // Space-separated, as in OAuth
+    #[serde(default)]
+    scope: String,
+}
+
+// Bearer tokens signed with HS256 and/or RS256 keys read from local files
+pub struct Jwt {
+    keys: Vec<(Algorithm, DecodingKey)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl Jwt {
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        validation
+    }
+
+    fn verify(&self, token: &str) -> Result<Principal, ShardError> {
+        let algorithm = decode_header(token)
+            .map_err(|err| ShardError::Unauthenticated(format!("malformed token: {}", err)))?
+            .alg;
+        let (_, key) = self.keys.iter()
+            .find(|(accepted, _)| *accepted == algorithm)
+            .ok_or_else(|| ShardError::Unauthenticated(format!("tokens signed with {:?} are not accepted", algorithm)))?;
+        let claims = decode::<Claims>(token, key, &self.validation(algorithm))
+            .map_err(|err| ShardError::Unauthenticated(format!("invalid token: {}", err)))?
+            .claims;
+        Ok(Principal {
+            subject: claims.sub,
+            permissions: claims.scope.split_whitespace().filter_map(Permission::parse).collect(),
+        })
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate(&self, headers: &HeaderMap) -> Option<Result<Principal, ShardError>> {
+        let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+        let token = value.strip_prefix("Bearer ").or_else(|| value.strip_prefix("bearer "))?;
+        Some(self.verify(token.trim()))
+    }
+}
+
+// The configured authenticators, tried in order; none configured leaves the API open
+#[derive(Clone, Default)]
+pub struct Auth {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+}
+
+fn read_file(key: &str) -> Result<Option<Vec<u8>>, String> {
+    match env::var(key) {
+        Ok(path) if !path.trim().is_empty() => fs::read(path.trim())
+            .map(Some)
+            .map_err(|err| format!("{} ({}): {}", key, path, err)),
+        _ => Ok(None),
+    }
+}
+
+impl Auth {
+    pub fn new(authenticators: Vec<Arc<dyn Authenticator>>) -> Self {
+        Auth { authenticators }
+    }
+
+    // AUTH_API_KEYS_FILE, AUTH_JWT_HS256_SECRET_FILE and AUTH_JWT_RS256_PUBLIC_KEY_FILE
+    // each enable a way in; AUTH_JWT_ISSUER and AUTH_JWT_AUDIENCE tighten token checks
+    pub fn from_env() -> Result<Self, String> {
+        let mut authenticators: Vec<Arc<dyn Authenticator>> = Vec::new();
+        if let Some(contents) = read_file("AUTH_API_KEYS_FILE")? {
+            let keys = ApiKeys::parse(&String::from_utf8_lossy(&contents)).map_err(|err| format!("AUTH_API_KEYS_FILE: {}", err))?;
+            info!("Loaded {} API keys", keys.keys.len());
+            authenticators.push(Arc::new(keys));
+        }
+
+        let mut keys = Vec::new();
+        if let Some(secret) = read_file("AUTH_JWT_HS256_SECRET_FILE")? {
+            keys.push((Algorithm::HS256, DecodingKey::from_secret(secret.trim_ascii())));
+        }
+        if let Some(pem) = read_file("AUTH_JWT_RS256_PUBLIC_KEY_FILE")? {
+            let key = DecodingKey::from_rsa_pem(&pem).map_err(|err| format!("AUTH_JWT_RS256_PUBLIC_KEY_FILE: {}", err))?;
+            keys.push((Algorithm::RS256, key));
+        }
+        if !keys.is_empty() {
+            authenticators.push(Arc::new(Jwt {
+                keys,
+                issuer: env::var("AUTH_JWT_ISSUER").ok(),
+                audience: env::var("AUTH_JWT_AUDIENCE").ok(),
+            }));
+        }
+
+        if authenticators.is_empty() {
+            warn!("No authentication configured, the HTTP and gRPC APIs are open");
+        }
+        Ok(Auth::new(authenticators))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.authenticators.is_empty()
+    }
+
+    fn principal(&self, headers: &HeaderMap) -> Result<Principal, ShardError> {
+        self.authenticators.iter()
+            .find_map(|authenticator| authenticator.authenticate(headers))
+            .unwrap_or_else(|| Err(unauthenticated("missing credentials")))
+    }
+}
+
+fn required_permission(method: &Method, path: &str) -> Permission {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) || (*method == Method::POST && READ_ONLY_POSTS.contains(&path)) {
+        Permission::Read
+    } else {
+        Permission::Write
+    }
+}
+
+// 401 without valid credentials, 403 when they lack the permission the route needs
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(auth) = req.app_data::<Data<Auth>>().cloned() else {
+        return next.call(req).await;
+    };
+    if !auth.is_enabled() || PUBLIC_PATHS.contains(&req.path()) {
+        return next.call(req).await;
+    }
+
+    let principal = auth.principal(req.headers())?;
+    principal.require(required_permission(req.method(), req.path()), &format!("{} {}", req.method(), req.path()))?;
+    req.extensions_mut().insert(principal);
+    next.call(req).await
+}
+
+// The credentials of a gRPC call, as the headers the authenticators read
+fn grpc_headers(metadata: &MetadataMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for name in ["authorization", API_KEY_HEADER] {
+        if let Some(value) = metadata.get(name).and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok()) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+    headers
+}
+
+// Interceptor for the client gRPC API: rejects calls without valid credentials and
+// leaves the caller's Principal on the request for `require_grpc`
+impl Interceptor for Auth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let principal = if self.is_enabled() { self.principal(&grpc_headers(request.metadata()))? } else { Principal::anonymous() };
+        request.extensions_mut().insert(principal);
+        Ok(request)
+    }
+}
+
+// Fails a gRPC call whose caller lacks `permission`. A call that never went through
+// the interceptor has no Principal and is turned away.
+pub fn require_grpc<T>(request: &Request<T>, permission: Permission, rpc: &str) -> Result<(), Status> {
+    let principal = request.extensions().get::<Principal>()
+        .ok_or_else(|| Status::unauthenticated("credentials were not checked"))?;
+    principal.require(permission, rpc).map_err(Status::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        scope: &'a str,
+        exp: u64,
+    }
+
+    fn headers(name: HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let keys = ApiKeys::parse("# comment\ningest k-1 read,write\n\ndashboard k-2 read\n").unwrap();
+        let auth = Auth::new(vec![Arc::new(keys)]);
+
+        let principal = auth.principal(&headers(HeaderName::from_static(API_KEY_HEADER), "k-2")).unwrap();
+        assert_eq!(principal.subject, "dashboard");
+        assert!(principal.permissions.contains(&Permission::Read));
+        assert!(!principal.permissions.contains(&Permission::Write));
+
+        assert!(matches!(auth.principal(&headers(HeaderName::from_static(API_KEY_HEADER), "k-3")), Err(ShardError::Unauthenticated(_))));
+        assert!(matches!(auth.principal(&HeaderMap::new()), Err(ShardError::Unauthenticated(_))));
+        assert!(ApiKeys::parse("ingest k-1 admin").is_err());
+        assert!(ApiKeys::parse("ingest k-1").is_err());
+    }
+
+    #[test]
+    fn test_hs256_token() {
+        let jwt = Jwt { keys: vec![(Algorithm::HS256, DecodingKey::from_secret(b"secret"))], issuer: None, audience: None };
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let token = encode(&Header::new(Algorithm::HS256), &TestClaims { sub: "svc", scope: "read write", exp }, &EncodingKey::from_secret(b"secret")).unwrap();
+
+        let principal = jwt.authenticate(&headers(header::AUTHORIZATION, &format!("Bearer {}", token))).unwrap().unwrap();
+        assert_eq!(principal.subject, "svc");
+        assert_eq!(principal.permissions.len(), 2);
+
+        let forged = encode(&Header::new(Algorithm::HS256), &TestClaims { sub: "svc", scope: "write", exp }, &EncodingKey::from_secret(b"other")).unwrap();
+        assert!(jwt.verify(&forged).is_err());
+        let expired = encode(&Header::new(Algorithm::HS256), &TestClaims { sub: "svc", scope: "read", exp: 1 }, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(jwt.verify(&expired).is_err());
+    }
+
+    #[test]
+    fn test_grpc_interceptor() {
+        let mut auth = Auth::new(vec![Arc::new(ApiKeys::parse("dashboard k-2 read").unwrap())]);
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert(API_KEY_HEADER, "k-2".parse().unwrap());
+        let request = auth.call(request).unwrap();
+        assert!(require_grpc(&request, Permission::Read, "GetLocation").is_ok());
+        assert_eq!(require_grpc(&request, Permission::Write, "PutLocation").unwrap_err().code(), tonic::Code::PermissionDenied);
+
+        assert_eq!(auth.call(Request::new(())).unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert_eq!(require_grpc(&Request::new(()), Permission::Read, "GetLocation").unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        let open = Auth::default().call(Request::new(())).unwrap();
+        assert!(require_grpc(&open, Permission::Write, "PutLocation").is_ok());
+    }
+
+    #[test]
+    fn test_required_permission() {
+        assert_eq!(required_permission(&Method::GET, "/loc-1"), Permission::Read);
+        assert_eq!(required_permission(&Method::POST, "/batch/get"), Permission::Read);
+        assert_eq!(required_permission(&Method::POST, "/batch"), Permission::Write);
+        assert_eq!(required_permission(&Method::DELETE, "/alerts/rules/1"), Permission::Write);
+    }
+}
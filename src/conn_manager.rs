@@ -1,11 +1,28 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use actix::prelude::*;
+use futures::future::join_all;
+use futures::FutureExt;
 use tonic::transport::{Channel, Endpoint};
-use log::info;
+use log::{error, info, warn};
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::HealthCheckRequest;
 
-use crate::util::parse_socket_addr;
+use crate::constants::TOTAL_SHARDS;
+use crate::dto::PeerStatus;
+use crate::health::RS_SERVICE;
+use crate::protocol::{self, Compatibility, ProtocolInfo};
+use crate::rs::rs::rs_client::RsClient;
+use crate::rs::rs::HandshakeRequest;
+use crate::tls;
+use crate::util::{parse_socket_addr, shard_node_id};
+
+const RPC_ERROR_WINDOW: Duration = Duration::from_secs(60);
+const PEER_PROBE_INTERVAL: Duration = Duration::from_secs(2);
+const PEER_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[derive(Message)]
 #[rtype(result = "Result<Channel, String>")]
@@ -15,16 +32,40 @@ pub struct GetChannel(pub u32);
 #[rtype(result = "()")]
 pub struct ResetChannel(pub u32);
 
+// Drops the channel even if it was reset within the debounce window
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ForceResetChannel(pub u32);
+
 #[derive(Message)]
 #[rtype(result = "Result<(u32, HashMap<u32, Channel>), ()>")]
 pub struct GetAllChannels;
 
+// Every node holding a shard of this node's locations, in shard order, with its channel
+// or why it can't take the shard. Writes and deletes count the latter as failed rather
+// than leaving the node out. Holders without a handshake yet get one first.
+#[derive(Message)]
+#[rtype(result = "Vec<(u32, Result<Channel, String>)>")]
+pub struct GetShardHolders;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordRpcError(pub u32);
+
+#[derive(Message)]
+#[rtype(result = "Vec<PeerStatus>")]
+pub struct GetPeerStatus;
+
 pub struct ChannelManager {
     current_node: u32,
     channels: HashMap<u32, Channel>,
     endpoints: Arc<HashMap<u32, String>>, // node_id -> endpoint URL
     reset_timers: HashMap<u32, Instant>, // For debouncing
     debounce_duration: Duration,
+    rpc_errors: HashMap<u32, VecDeque<Instant>>, // failures within RPC_ERROR_WINDOW
+    reachable: HashMap<u32, bool>, // outcome of the last probe of each peer
+    compatibility: HashMap<u32, Compatibility>, // from the last handshake with each peer
+    serving: HashMap<u32, bool>, // rs.Rs health from the last probe of each peer
 }
 
 impl ChannelManager {
@@ -35,6 +76,133 @@ impl ChannelManager {
             endpoints: endpoints,
             reset_timers: HashMap::new(),
             debounce_duration,
+            rpc_errors: HashMap::new(),
+            reachable: HashMap::new(),
+            compatibility: HashMap::new(),
+            serving: HashMap::new(),
+        }
+    }
+
+    // A peer takes RPCs once a handshake showed it compatible, until it answers
+    // NOT_SERVING; a peer that hasn't answered a health check yet is tried
+    fn routable(&self, node_id: u32) -> Result<(), String> {
+        if node_id == self.current_node {
+            return Ok(());
+        }
+        match self.compatibility(node_id) {
+            Compatibility::Incompatible(reason) => return Err(format!("Node {} is incompatible: {}", node_id, reason)),
+            Compatibility::Unknown => return Err(format!("Node {} hasn't completed a handshake yet", node_id)),
+            Compatibility::Compatible | Compatibility::Degraded(_) => {}
+        }
+        if self.serving.get(&node_id) == Some(&false) {
+            return Err(format!("Node {} is not serving", node_id));
+        }
+        Ok(())
+    }
+
+    fn compatibility(&self, node_id: u32) -> Compatibility {
+        self.compatibility.get(&node_id).cloned().unwrap_or_default()
+    }
+
+    // None when the peer didn't answer, it keeps its last compatibility then
+    fn record_handshake(&mut self, node_id: u32, compatibility: Option<Compatibility>) {
+        let reachable = compatibility.is_some();
+        if self.reachable.insert(node_id, reachable) != Some(reachable) {
+            info!("Node {} is now {}", node_id, if reachable { "reachable" } else { "unreachable" });
+        }
+        let Some(compatibility) = compatibility else {
+            return;
+        };
+        if self.compatibility.get(&node_id) == Some(&compatibility) {
+            return;
+        }
+        match &compatibility {
+            Compatibility::Incompatible(reason) => error!("Node {} is incompatible, leaving it out: {}", node_id, reason),
+            Compatibility::Degraded(reason) => warn!("Node {} runs {}", node_id, reason),
+            _ => info!("Node {} is compatible", node_id),
+        }
+        self.compatibility.insert(node_id, compatibility);
+    }
+
+    // Shakes hands right away with the given peers that haven't completed a handshake,
+    // so an RPC to a peer that just came up waits for it instead of the next probe
+    fn handshake_unknown(&mut self, node_ids: &[u32]) -> impl Future<Output = Vec<(u32, Option<Compatibility>)>> {
+        let current_node = self.current_node;
+        let unknown: Vec<u32> = node_ids.iter()
+            .cloned()
+            .filter(|node_id| *node_id != current_node && self.compatibility(*node_id) == Compatibility::Unknown)
+            .collect();
+        let handshakes: Vec<_> = unknown.into_iter()
+            .filter_map(|node_id| self.get_or_create_lazy_channel(node_id).ok().map(|channel| (node_id, channel)))
+            .map(|(node_id, channel)| handshake(current_node, node_id, channel).map(move |compatibility| (node_id, compatibility)))
+            .collect();
+        join_all(handshakes)
+    }
+
+    fn routed_channel(&mut self, node_id: u32) -> Result<Channel, String> {
+        self.routable(node_id)?;
+        self.get_or_create_lazy_channel(node_id)
+    }
+
+    fn probe_peers(&mut self, ctx: &mut Context<Self>) {
+        let peers: Vec<u32> = self.endpoints.keys()
+            .cloned()
+            .filter(|node_id| *node_id != self.current_node)
+            .collect();
+
+        for node_id in peers {
+            let channel = match self.get_or_create_lazy_channel(node_id) {
+                Ok(channel) => channel,
+                Err(err) => {
+                    warn!("Cannot probe node {}: {}", node_id, err);
+                    self.reachable.insert(node_id, false);
+                    continue;
+                }
+            };
+
+            // The handshake doubles as the liveness probe, so a peer restarted on
+            // another version is noticed even though its channel never broke
+            let health_channel = channel.clone();
+            let handshake = handshake(self.current_node, node_id, channel);
+            let health = async move {
+                let mut client = HealthClient::new(health_channel);
+                let mut request = tonic::Request::new(HealthCheckRequest { service: RS_SERVICE.to_owned() });
+                request.set_timeout(PEER_PROBE_TIMEOUT);
+                match tokio::time::timeout(PEER_PROBE_TIMEOUT, client.check(request)).await {
+                    Ok(Ok(res)) => Some(res.get_ref().status == ServingStatus::Serving as i32),
+                    // Nodes from before the health service keep being routed to while reachable
+                    Ok(Err(status)) if status.code() == tonic::Code::Unimplemented => Some(true),
+                    _ => None,
+                }
+            };
+
+            ctx.spawn(futures::future::join(handshake, health).into_actor(self).map(move |(compatibility, serving), act, _ctx| {
+                act.record_handshake(node_id, compatibility);
+                // An unreachable peer keeps its last answer, failed RPCs reset its channel anyway
+                if let Some(serving) = serving {
+                    if act.serving.insert(node_id, serving) != Some(serving) {
+                        info!("Node {} is now {}", node_id, if serving { "serving" } else { "not serving" });
+                    }
+                }
+            }));
+        }
+    }
+
+    fn reset(&mut self, node_id: u32, now: Instant) {
+        info!("Resetting channel for node: {}", node_id);
+        self.channels.remove(&node_id);
+        self.reset_timers.insert(node_id, now);
+    }
+
+    fn recent_rpc_errors(&mut self, node_id: u32, now: Instant) -> usize {
+        match self.rpc_errors.get_mut(&node_id) {
+            Some(errors) => {
+                while errors.front().map_or(false, |at| now.duration_since(*at) > RPC_ERROR_WINDOW) {
+                    errors.pop_front();
+                }
+                errors.len()
+            }
+            None => 0,
         }
     }
 
@@ -45,10 +213,11 @@ impl ChannelManager {
         }
         
         if let Some(endpoint_url) = self.endpoints.get(&node_id) {
-            let (ip, port) = parse_socket_addr(&endpoint_url).unwrap();
+            let (ip, port) = parse_socket_addr(&endpoint_url)
+                .map_err(|e| format!("Invalid endpoint address {}: {}", endpoint_url, e))?;
             match Endpoint::from_shared(format!("http://{}:{}", ip, port+80)) {
                 Ok(endpoint) => {
-                    let channel = endpoint.connect_lazy();
+                    let channel = tls::lazy_channel(endpoint);
                     
                     self.channels.insert(node_id, channel.clone());
                     Ok(channel)
@@ -61,22 +230,53 @@ impl ChannelManager {
     }
 }
 
+// Asks a peer for its protocol info, None when it doesn't answer in time
+async fn handshake(current_node: u32, node_id: u32, channel: Channel) -> Option<Compatibility> {
+    let mut client = RsClient::new(channel);
+    let mut request = tonic::Request::new(HandshakeRequest {
+        node_id: current_node,
+        info: Some(ProtocolInfo::local().to_proto()),
+    });
+    request.set_timeout(PEER_PROBE_TIMEOUT);
+    match tokio::time::timeout(PEER_PROBE_TIMEOUT, client.handshake(request)).await {
+        Ok(Ok(res)) => Some(match &res.get_ref().info {
+            Some(info) => {
+                protocol::record_peer_version(node_id, info.protocol_version);
+                ProtocolInfo::local().compatibility(&ProtocolInfo::from_proto(info))
+            }
+            None => Compatibility::Incompatible("handshake carried no protocol info".to_owned()),
+        }),
+        Ok(Err(status)) if status.code() == tonic::Code::Unimplemented => {
+            protocol::record_peer_version(node_id, ProtocolInfo::legacy().protocol_version);
+            Some(ProtocolInfo::local().compatibility(&ProtocolInfo::legacy()))
+        }
+        _ => None,
+    }
+}
+
 impl Actor for ChannelManager {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("ChannelManager actor started");
+        self.probe_peers(ctx);
+        ctx.run_interval(PEER_PROBE_INTERVAL, |act, ctx| act.probe_peers(ctx));
     }
 }
 
 
 
 impl Handler<GetChannel> for ChannelManager {
-    type Result = Result<Channel, String>;
+    type Result = ResponseActFuture<Self, Result<Channel, String>>;
 
     fn handle(&mut self, msg: GetChannel, _ctx: &mut Context<Self>) -> Self::Result {
         let node_id = msg.0;
-        self.get_or_create_lazy_channel(node_id)
+        Box::pin(self.handshake_unknown(&[node_id]).into_actor(self).map(move |handshakes, act, _ctx| {
+            for (node_id, compatibility) in handshakes {
+                act.record_handshake(node_id, compatibility);
+            }
+            act.routed_channel(node_id)
+        }))
     }
 }
 
@@ -95,9 +295,15 @@ impl Handler<ResetChannel> for ChannelManager {
             }
         }
         
-        info!("Resetting channel for node: {}", node_id);
-        self.channels.remove(&node_id);
-        self.reset_timers.insert(node_id, now);
+        self.reset(node_id, now);
+    }
+}
+
+impl Handler<ForceResetChannel> for ChannelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForceResetChannel, _ctx: &mut Context<Self>) -> Self::Result {
+        self.reset(msg.0, Instant::now());
     }
 }
 
@@ -117,7 +323,64 @@ impl Handler<GetAllChannels> for ChannelManager {
                 info!("Failed to create channel for node: {}", node_id);
             }
         }
-        Ok((self.current_node, self.channels.clone()))
+        let usable = self.channels.iter()
+            .filter(|(node_id, _)| self.routable(**node_id).is_ok())
+            .map(|(node_id, channel)| (*node_id, channel.clone()))
+            .collect();
+        Ok((self.current_node, usable))
+    }
+}
+
+impl Handler<GetShardHolders> for ChannelManager {
+    type Result = ResponseActFuture<Self, Vec<(u32, Result<Channel, String>)>>;
+
+    fn handle(&mut self, _msg: GetShardHolders, _ctx: &mut Context<Self>) -> Self::Result {
+        let holders: Vec<u32> = (0..TOTAL_SHARDS).map(|i| shard_node_id(self.current_node, i)).collect();
+        Box::pin(self.handshake_unknown(&holders).into_actor(self).map(move |handshakes, act, _ctx| {
+            for (node_id, compatibility) in handshakes {
+                act.record_handshake(node_id, compatibility);
+            }
+            holders.into_iter().map(|node_id| (node_id, act.routed_channel(node_id))).collect()
+        }))
+    }
+}
+
+impl Handler<RecordRpcError> for ChannelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordRpcError, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        self.recent_rpc_errors(msg.0, now);
+        self.rpc_errors.entry(msg.0).or_default().push_back(now);
+    }
+}
+
+impl Handler<GetPeerStatus> for ChannelManager {
+    type Result = MessageResult<GetPeerStatus>;
+
+    fn handle(&mut self, _msg: GetPeerStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        let mut node_ids: Vec<u32> = self.endpoints.keys().cloned().collect();
+        node_ids.sort();
+
+        let mut peers = Vec::with_capacity(node_ids.len());
+        for node_id in node_ids {
+            let last_reset = self.reset_timers.get(&node_id).cloned();
+            peers.push(PeerStatus {
+                node_id,
+                endpoint: self.endpoints.get(&node_id).cloned().unwrap_or_default(),
+                is_self: node_id == self.current_node,
+                connected: self.channels.contains_key(&node_id),
+                reset: last_reset.is_some(),
+                last_reset_ms_ago: last_reset.map(|at| now.duration_since(at).as_millis() as u64),
+                recent_rpc_errors: self.recent_rpc_errors(node_id, now),
+                reachable: node_id == self.current_node || self.reachable.get(&node_id).cloned().unwrap_or(false),
+                serving: node_id == self.current_node || self.serving.get(&node_id).cloned().unwrap_or(false),
+                compatibility: if node_id == self.current_node { Compatibility::Compatible } else { self.compatibility(node_id) },
+            });
+        }
+
+        MessageResult(peers)
     }
 }
 
@@ -300,4 +563,20 @@ fn test_endpoint(port: u16) -> String {
     //         assert!(result.is_ok(), "Should successfully get channel through RootActor integration");
     //     });
     // }
+
+    #[actix_rt::test]
+    async fn test_shard_holders_keep_unroutable_peers() {
+        let endpoints = (0..7).map(|node_id| (node_id, format!("127.0.0.1:{}", 1 + node_id))).collect();
+        let mut manager = ChannelManager::new(0, Arc::new(endpoints), Duration::from_millis(100));
+        manager.compatibility.insert(1, Compatibility::Compatible);
+        manager.compatibility.insert(2, Compatibility::Incompatible("newer shard format".to_owned()));
+        manager.compatibility.insert(3, Compatibility::Compatible);
+        manager.serving.insert(3, false);
+
+        let holders = manager.start().send(GetShardHolders).await.unwrap();
+        assert_eq!(holders.iter().map(|(node_id, _)| *node_id).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6]);
+        assert!(holders[0].1.is_ok());
+        // Left in with the reason, so a write or delete counts their shards as failed
+        assert!(holders[1..].iter().all(|(_, channel)| channel.is_err()));
+    }
 }
@@ -1 +1,17 @@
+use std::time::Duration;
+
 pub const ROOT_ACTOR_POOL_SIZE: u32 = 2;
+// Shards needed to reconstruct a location (4-of-6 Reed-Solomon)
+pub const REQUIRED_SHARDS: usize = 4;
+// Shards written per location, one per node other than the owner
+pub const TOTAL_SHARDS: usize = 6;
+// Upper bounds for batch requests and how many of their writes run at once
+pub const MAX_BATCH_SIZE: usize = 5000;
+pub const BATCH_WRITE_CONCURRENCY: usize = 64;
+pub const BATCH_READ_CONCURRENCY: usize = 64;
+// Page sizes for GET /locations
+pub const DEFAULT_LIST_LIMIT: usize = 100;
+pub const MAX_LIST_LIMIT: usize = 1000;
+// Shard writes to the same node within this window go out as one writeShardBatch call
+pub const SHARD_BATCH_WINDOW: Duration = Duration::from_millis(2);
+pub const MAX_SHARD_BATCH: usize = 256;
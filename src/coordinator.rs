@@ -0,0 +1,590 @@
+use std::collections::{HashMap, HashSet};
+use std::future;
+use std::sync::Arc;
+use actix::Addr;
+use futures::future::join_all;
+use futures::stream::{self, BoxStream, StreamExt};
+use futures::FutureExt;
+use log::error;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+
+use crate::conn_manager::{ChannelManager, GetAllChannels, GetChannel, RecordRpcError, ResetChannel};
+use crate::constants::{BATCH_READ_CONCURRENCY, BATCH_WRITE_CONCURRENCY, MAX_BATCH_SIZE, REQUIRED_SHARDS, TOTAL_SHARDS};
+use crate::dto::{BatchPutItem, IfMatch, EnrichedLocationStats, LocationPatch, LocationStats, ShardError};
+use crate::feed::{self, LocationChange, Subscription, WatchFilter};
+use crate::location_actor::GetLocation;
+use crate::node::{from_location_result, from_write_result};
+use crate::root_actor::{delete_location, get_location, lookup, patch_location, put_location, RootActor};
+use crate::rpc::{self, RpcKind};
+use crate::rs;
+use crate::rs::rs::rs_client::RsClient;
+use crate::rs::rs::{GetLocationsRequest, GetShardRequest, GetShardResponse, RouteDeleteRequest, RoutePatchRequest, RouteWriteBatchRequest, RouteWriteRequest};
+use crate::trace::{Span, SpanKind, TraceContext};
+use crate::util::{get_owner_node_id, shard_node_id};
+use crate::validation::{validate_location_id, validate_write, ValidationConfig};
+
+// Routes client reads and writes to the owner of each location and rebuilds reads
+// from shards when the owner can't answer. Shared by the HTTP and gRPC front ends.
+#[derive(Clone)]
+pub struct Coordinator {
+    pub current_node: u32,
+    pub root_actor: Vec<Addr<RootActor>>,
+    pub channel_manager: Arc<Addr<ChannelManager>>,
+    pub validation: ValidationConfig,
+}
+
+impl Coordinator {
+    async fn client(&self, node_id: u32) -> Result<RsClient<Channel>, ShardError> {
+        let channel = self.channel_manager
+            .send(GetChannel(node_id))
+            .await?
+            .map_err(ShardError::ChannelError)?;
+        Ok(RsClient::new(channel))
+    }
+
+    fn rpc_failed(&self, node_id: u32, status: Status) -> ShardError {
+        rpc_failed(&self.channel_manager, node_id, status)
+    }
+
+    // Commits a write on the owner, forwarding it with routeWrite when that is another node.
+    // With `expected` set the write only applies if the location passes that If-Match condition.
+    // A routed write bumps the count, so it isn't retried; it only gets a deadline.
+    pub async fn put(&self, location_id: String, stats: LocationStats, expected: Option<IfMatch>, trace: TraceContext) -> Result<EnrichedLocationStats, ShardError> {
+        validate_write(&location_id, &stats, &self.validation)?;
+        let owner_id = get_owner_node_id(location_id.clone());
+        if owner_id == self.current_node {
+            return put_location(&self.root_actor, self.channel_manager.clone(), location_id, stats, expected, trace).await;
+        }
+
+        let mut span = Span::start("rpc.route_write", Some(&trace), SpanKind::Client);
+        span.set_attribute("peer.node_id", owner_id);
+
+        let (expected_modification_count, expected_modification_counts, expect_exists) = IfMatch::to_proto(expected.as_ref());
+        let route = RouteWriteRequest {
+            location_id,
+            id: stats.id.clone(),
+            seismic_activity: stats.seismic_activity,
+            temperature_c: stats.temperature_c,
+            radiation_level: stats.radiation_level,
+            expected_modification_count,
+            expected_modification_counts,
+            expect_exists,
+        };
+        let client = self.client(owner_id).await?;
+        let response = rpc::call(RpcKind::Route, &span.context(), false, |timeout| {
+            let mut client = client.clone();
+            let mut request = Request::new(route.clone());
+            span.context().inject(&mut request);
+            request.set_timeout(timeout);
+            async move { client.route_write(request).await }
+        }).await;
+
+        match response {
+            Ok(res) => Ok(EnrichedLocationStats::from(res.into_inner().modification_count, stats)),
+            Err(status) => {
+                span.set_error(&status);
+                error!("Failed to route write to node {}: {}", owner_id, status);
+                Err(self.rpc_failed(owner_id, status))
+            }
+        }
+    }
+
+    pub async fn patch(&self, location_id: String, patch: LocationPatch, expected: Option<IfMatch>, trace: TraceContext) -> Result<EnrichedLocationStats, ShardError> {
+        validate_write(&location_id, &patch, &self.validation)?;
+        let owner_id = get_owner_node_id(location_id.clone());
+        if owner_id == self.current_node {
+            return patch_location(&self.root_actor, self.channel_manager.clone(), location_id, patch, expected, trace).await;
+        }
+
+        let mut span = Span::start("rpc.route_patch", Some(&trace), SpanKind::Client);
+        span.set_attribute("peer.node_id", owner_id);
+
+        let (expected_modification_count, expected_modification_counts, expect_exists) = IfMatch::to_proto(expected.as_ref());
+        let route = RoutePatchRequest {
+            location_id: location_id.clone(),
+            id: patch.id,
+            seismic_activity: patch.seismic_activity,
+            temperature_c: patch.temperature_c,
+            radiation_level: patch.radiation_level,
+            expected_modification_count,
+            expected_modification_counts,
+            expect_exists,
+        };
+        let client = self.client(owner_id).await?;
+        let response = rpc::call(RpcKind::Route, &span.context(), false, |timeout| {
+            let mut client = client.clone();
+            let mut request = Request::new(route.clone());
+            span.context().inject(&mut request);
+            request.set_timeout(timeout);
+            async move { client.route_patch(request).await }
+        }).await;
+
+        match response {
+            Ok(res) => res.into_inner().location_stats
+                .map(EnrichedLocationStats::from_proto)
+                .ok_or_else(|| ShardError::RpcError(format!("No stats for {} in response", location_id))),
+            Err(status) => {
+                span.set_error(&status);
+                Err(self.rpc_failed(owner_id, status))
+            }
+        }
+    }
+
+    // Serves the owner record when this node holds it, otherwise rebuilds the location
+    // from the peers' shards. A missing local record or shard isn't final, the rebuild
+    // only reports not found when no node has a shard.
+    pub async fn get(&self, location_id: String, trace: TraceContext) -> Result<EnrichedLocationStats, ShardError> {
+        let addr = lookup(&self.root_actor, &location_id).await?;
+        if let Ok(location_stats) = addr.send(GetLocation).await? {
+            return Ok(location_stats);
+        }
+
+        read_from_peers(self.channel_manager.clone(), location_id, trace).await
+    }
+
+    pub async fn delete(&self, location_id: String, trace: TraceContext) -> Result<(), ShardError> {
+        validate_location_id(&location_id, &self.validation)?;
+        let owner_id = get_owner_node_id(location_id.clone());
+        if owner_id == self.current_node {
+            return delete_location(&self.root_actor, self.channel_manager.clone(), location_id, trace).await;
+        }
+
+        let mut span = Span::start("rpc.route_delete", Some(&trace), SpanKind::Client);
+        span.set_attribute("peer.node_id", owner_id);
+
+        let client = self.client(owner_id).await?;
+        let response = rpc::call(RpcKind::Route, &span.context(), false, |timeout| {
+            let mut client = client.clone();
+            let mut request = Request::new(RouteDeleteRequest { location_id: location_id.clone() });
+            span.context().inject(&mut request);
+            request.set_timeout(timeout);
+            async move { client.route_delete(request).await }
+        }).await;
+
+        match response {
+            Ok(_) => Ok(()),
+            Err(status) => {
+                span.set_error(&status);
+                error!("Failed to route delete to node {}: {}", owner_id, status);
+                Err(self.rpc_failed(owner_id, status))
+            }
+        }
+    }
+
+    // Opens a change stream on a node that owns some of the watched locations
+    pub async fn subscribe_remote(&self, node_id: u32, filter: &WatchFilter, trace: TraceContext) -> Result<tonic::Streaming<rs::rs::LocationChange>, ShardError> {
+        let mut request = Request::new(filter.to_proto());
+        trace.inject(&mut request);
+
+        match self.client(node_id).await?.subscribe(request).await {
+            Ok(res) => Ok(res.into_inner()),
+            Err(status) => Err(self.rpc_failed(node_id, status)),
+        }
+    }
+
+    // Merges the change streams of every node that can commit a watched location. The
+    // stream ends with an error as soon as one of them is lost, so a watcher never
+    // silently misses changes; it reconnects resuming from the last counts it saw.
+    pub async fn watch(&self, filter: WatchFilter, trace: TraceContext) -> Result<BoxStream<'static, Result<LocationChange, ShardError>>, ShardError> {
+        // Any node owns some locations under a prefix: the owner plus one per shard
+        let nodes: Vec<u32> = match filter.owners() {
+            Some(owners) => owners.into_iter().collect(),
+            None => (0..=TOTAL_SHARDS as u32).collect(),
+        };
+
+        let mut streams = Vec::with_capacity(nodes.len());
+        for node_id in nodes {
+            // Each node checks the resume points of the locations it owns
+            let filter = filter.for_owner(node_id);
+            if node_id == self.current_node {
+                let subscription = feed::subscribe(filter).await?;
+                streams.push(local_changes(node_id, subscription));
+            } else {
+                let changes = self.subscribe_remote(node_id, &filter, trace.clone()).await?;
+                streams.push(remote_changes(node_id, changes));
+            }
+        }
+
+        let changes = stream::select_all(streams).scan(false, |failed, change| {
+            if *failed {
+                return future::ready(None);
+            }
+            *failed = change.is_err();
+            future::ready(Some(change))
+        });
+        Ok(changes.boxed())
+    }
+
+    // Writes a batch with one call per owner node. Each item gets its own result, in input order.
+    pub async fn put_batch(&self, items: Vec<BatchPutItem>, trace: TraceContext) -> Result<Vec<(String, Result<(), ShardError>)>, ShardError> {
+        if items.len() > MAX_BATCH_SIZE {
+            return Err(ShardError::InvalidInput(format!("Batch of {} items exceeds the limit of {}", items.len(), MAX_BATCH_SIZE)));
+        }
+
+        let mut results: Vec<Option<(String, Result<(), ShardError>)>> = vec![None; items.len()];
+        let mut groups: HashMap<u32, Vec<(usize, BatchPutItem)>> = HashMap::new();
+        for (i, item) in items.into_iter().enumerate() {
+            if let Err(err) = validate_write(&item.location_id, &item.stats, &self.validation) {
+                results[i] = Some((item.location_id, Err(err)));
+                continue;
+            }
+            groups.entry(get_owner_node_id(item.location_id.clone())).or_default().push((i, item));
+        }
+
+        let writes = groups.into_iter().map(|(owner_id, group)| self.write_group(owner_id, group, trace.clone()));
+        for (i, location_id, result) in join_all(writes).await.into_iter().flatten() {
+            results[i] = Some((location_id, result));
+        }
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    // Writes one owner's share of a batch, locally or with a single routeWriteBatch call
+    async fn write_group(&self, owner_id: u32, group: Vec<(usize, BatchPutItem)>, trace: TraceContext) -> Vec<(usize, String, Result<(), ShardError>)> {
+        if owner_id == self.current_node {
+            return stream::iter(group.into_iter().map(|(i, item)| {
+                let trace = trace.clone();
+                async move {
+                    let result = put_location(&self.root_actor, self.channel_manager.clone(), item.location_id.clone(), item.stats, IfMatch::from_count(item.expected_modification_count), trace).await.map(|_| ());
+                    (i, item.location_id, result)
+                }
+            })).buffered(BATCH_WRITE_CONCURRENCY).collect().await;
+        }
+
+        let mut rpc_span = Span::start("rpc.route_write_batch", Some(&trace), SpanKind::Client);
+        rpc_span.set_attribute("peer.node_id", owner_id);
+        rpc_span.set_attribute("batch.size", group.len());
+
+        let (indexes, writes): (Vec<usize>, Vec<RouteWriteRequest>) = group.into_iter()
+            .map(|(i, item)| (i, RouteWriteRequest {
+                location_id: item.location_id,
+                id: item.stats.id,
+                seismic_activity: item.stats.seismic_activity,
+                temperature_c: item.stats.temperature_c,
+                radiation_level: item.stats.radiation_level,
+                expected_modification_count: item.expected_modification_count,
+                expected_modification_counts: Vec::new(),
+                expect_exists: false,
+            }))
+            .unzip();
+        let location_ids: Vec<String> = writes.iter().map(|write| write.location_id.clone()).collect();
+
+        let batch = RouteWriteBatchRequest { writes };
+        let response = match self.client(owner_id).await {
+            Ok(client) => rpc::call(RpcKind::Route, &rpc_span.context(), false, |timeout| {
+                let mut client = client.clone();
+                let mut request = Request::new(batch.clone());
+                rpc_span.context().inject(&mut request);
+                request.set_timeout(timeout);
+                async move { client.route_write_batch(request).await }
+            }).await.map_err(|status| self.rpc_failed(owner_id, status)),
+            Err(err) => Err(err),
+        };
+
+        match response {
+            Ok(res) => {
+                let mut remote = res.into_inner().results.into_iter();
+                indexes.into_iter().zip(location_ids).map(|(i, location_id)| {
+                    let result = remote.next()
+                        .ok_or_else(|| ShardError::RpcError(format!("No result for {} from node {}", location_id, owner_id)))
+                        .and_then(|result| from_write_result(&result));
+                    (i, location_id, result)
+                }).collect()
+            }
+            Err(err) => {
+                rpc_span.set_error(&err);
+                error!("Failed to route batch of {} writes to node {}: {}", indexes.len(), owner_id, err);
+                indexes.into_iter().zip(location_ids)
+                    .map(|(i, location_id)| (i, location_id, Err(err.clone())))
+                    .collect()
+            }
+        }
+    }
+
+    // Reads a batch with one call per owner node. Duplicate ids are read once; results
+    // follow the order each id first appeared in.
+    pub async fn get_batch(&self, location_ids: Vec<String>, trace: TraceContext) -> Result<Vec<(String, Result<EnrichedLocationStats, ShardError>)>, ShardError> {
+        if location_ids.len() > MAX_BATCH_SIZE {
+            return Err(ShardError::InvalidInput(format!("Batch of {} ids exceeds the limit of {}", location_ids.len(), MAX_BATCH_SIZE)));
+        }
+
+        let mut order: Vec<String> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut results: HashMap<String, Result<EnrichedLocationStats, ShardError>> = HashMap::new();
+        let mut groups: HashMap<u32, Vec<String>> = HashMap::new();
+        for location_id in location_ids {
+            if !seen.insert(location_id.clone()) {
+                continue;
+            }
+            order.push(location_id.clone());
+            if let Err(err) = validate_location_id(&location_id, &self.validation) {
+                results.insert(location_id, Err(err));
+                continue;
+            }
+            groups.entry(get_owner_node_id(location_id.clone())).or_default().push(location_id);
+        }
+
+        let reads = groups.into_iter().map(|(owner_id, group)| self.read_group(owner_id, group, trace.clone()));
+        results.extend(join_all(reads).await.into_iter().flatten());
+
+        Ok(order.into_iter().map(|location_id| {
+            let result = results.remove(&location_id)
+                .unwrap_or_else(|| Err(ShardError::RpcError(format!("No result for {}", location_id))));
+            (location_id, result)
+        }).collect())
+    }
+
+    // Reads one owner's share of a multi-get, locally or with a single getLocations call.
+    // Ids the owner could not answer are rebuilt from shards one by one.
+    async fn read_group(&self, owner_id: u32, group: Vec<String>, trace: TraceContext) -> Vec<(String, Result<EnrichedLocationStats, ShardError>)> {
+        if owner_id == self.current_node {
+            return stream::iter(group.into_iter().map(|location_id| {
+                let trace = trace.clone();
+                async move {
+                    let result = match get_location(&self.root_actor, &location_id).await {
+                        Ok(stats) => Ok(stats),
+                        Err(_) => read_from_peers(self.channel_manager.clone(), location_id.clone(), trace).await,
+                    };
+                    (location_id, result)
+                }
+            })).buffered(BATCH_READ_CONCURRENCY).collect().await;
+        }
+
+        let mut rpc_span = Span::start("rpc.get_locations", Some(&trace), SpanKind::Client);
+        rpc_span.set_attribute("peer.node_id", owner_id);
+        rpc_span.set_attribute("batch.size", group.len());
+
+        let response = match self.client(owner_id).await {
+            Ok(client) => rpc::call(RpcKind::Route, &rpc_span.context(), true, |timeout| {
+                let mut client = client.clone();
+                let mut request = Request::new(GetLocationsRequest { location_ids: group.clone() });
+                rpc_span.context().inject(&mut request);
+                request.set_timeout(timeout);
+                async move { client.get_locations(request).await }
+            }).await.map_err(|status| self.rpc_failed(owner_id, status)),
+            Err(err) => Err(err),
+        };
+
+        let mut remote: HashMap<String, Result<EnrichedLocationStats, ShardError>> = match response {
+            Ok(res) => res.into_inner().results.into_iter()
+                .map(|result| (result.location_id.clone(), from_location_result(result)))
+                .collect(),
+            Err(err) => {
+                rpc_span.set_error(&err);
+                error!("Failed to get {} locations from node {}: {}", group.len(), owner_id, err);
+                HashMap::new()
+            }
+        };
+
+        stream::iter(group.into_iter().map(|location_id| {
+            let owner_result = remote.remove(&location_id);
+            let channel_manager = self.channel_manager.clone();
+            let trace = rpc_span.context();
+            async move {
+                let result = match owner_result {
+                    Some(Ok(stats)) => Ok(stats),
+                    Some(Err(ShardError::NotFoundError(msg))) => Err(ShardError::NotFoundError(msg)),
+                    _ => read_from_peers(channel_manager, location_id.clone(), trace).await,
+                };
+                (location_id, result)
+            }
+        })).buffered(BATCH_READ_CONCURRENCY).collect().await
+    }
+}
+
+// This node's change stream, ending with an error once the feed drops the subscriber
+// for falling behind or on shutdown
+fn local_changes(node_id: u32, subscription: Subscription) -> BoxStream<'static, Result<LocationChange, ShardError>> {
+    let lost = stream::once(async move {
+        let err = ShardError::Unavailable(format!("Node {} dropped the subscription", node_id));
+        error!("Lost local change subscription: {}", err);
+        Err(err)
+    });
+    feed::changes(subscription).map(Ok).chain(lost).boxed()
+}
+
+// A peer's change stream, ending with an error once the peer goes away
+fn remote_changes(node_id: u32, changes: tonic::Streaming<rs::rs::LocationChange>) -> BoxStream<'static, Result<LocationChange, ShardError>> {
+    stream::unfold(Some(changes), move |changes| async move {
+        let mut changes = changes?;
+        let err = match changes.message().await {
+            Ok(Some(change)) => match LocationChange::from_proto(change) {
+                Ok(change) => return Some((Ok(change), Some(changes))),
+                Err(err) => err,
+            },
+            Ok(None) => ShardError::Unavailable(format!("Node {} closed the subscription", node_id)),
+            Err(status) => ShardError::from(status),
+        };
+        error!("Lost change subscription to node {}: {}", node_id, err);
+        Some((Err(err), None))
+    }).boxed()
+}
+
+async fn read_shard_from_node(
+    addr: Arc<Addr<ChannelManager>>,
+    node_id: u32,
+    channel: Channel,
+    location_id: String,
+    parent: TraceContext,
+) -> Result<GetShardResponse, ShardError> {
+    let client = RsClient::new(channel);
+    let mut span = Span::start("rpc.get_shard", Some(&parent), SpanKind::Client);
+    span.set_attribute("peer.node_id", node_id);
+
+    let response = rpc::call(RpcKind::ShardRead, &span.context(), true, |timeout| {
+        let mut client = client.clone();
+        let mut request = Request::new(GetShardRequest { location_id: location_id.clone() });
+        span.context().inject(&mut request);
+        request.set_timeout(timeout);
+        async move { client.get_shard_request(request).await }
+    }).await;
+
+    match response {
+        Ok(res) => Ok(res.into_inner()),
+        Err(status) => {
+            record_rpc_failure(&addr, node_id, &status);
+            
+            span.set_error(&status);
+            error!("Failed to read shard from node {}: {}", node_id, status);
+            Ok(GetShardResponse {
+                shard: None,
+                location_stats: None,
+            })
+        }
+    }
+}
+
+// Records a failed call to a peer, dropping its channel when the connection broke
+pub fn record_rpc_failure(channel_manager: &Addr<ChannelManager>, node_id: u32, status: &Status) {
+    if is_connection_error(status) {
+        channel_manager.do_send(ResetChannel(node_id));
+    }
+    channel_manager.do_send(RecordRpcError(node_id));
+}
+
+pub fn rpc_failed(channel_manager: &Addr<ChannelManager>, node_id: u32, status: Status) -> ShardError {
+    record_rpc_failure(channel_manager, node_id, &status);
+    ShardError::from(status)
+}
+
+// Failures worth dropping the peer's channel for; transport errors surface as Unknown
+pub fn is_connection_error(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable |
+        tonic::Code::DeadlineExceeded |
+        tonic::Code::Cancelled |
+        tonic::Code::Aborted |
+        tonic::Code::Unknown
+    )
+}
+
+
+// Asks every node for its shard of a location and rebuilds the record, preferring
+// the owner's copy when it answers
+pub async fn read_from_peers(channel_manager: Arc<Addr<ChannelManager>>, location_id: String, parent: TraceContext) -> Result<EnrichedLocationStats, ShardError> {
+    let mut span = Span::start("location.reconstruct", Some(&parent), SpanKind::Internal);
+    span.set_attribute("location_id", &location_id);
+
+    let result = reconstruct(channel_manager, location_id, &mut span).await;
+    if let Err(err) = &result {
+        span.set_error(err);
+    }
+    result
+}
+
+async fn reconstruct(channel_manager: Arc<Addr<ChannelManager>>, location_id: String, span: &mut Span) -> Result<EnrichedLocationStats, ShardError> {
+    let (_, channels) = channel_manager
+        .send(GetAllChannels {})
+        .await?
+        .map_err(|_| ShardError::ChannelError("Failed to list channels".to_owned()))?;
+
+    let reads = channels.into_iter().map(|(node_id, channel)| {
+        read_shard_from_node(channel_manager.clone(), node_id, channel, location_id.clone(), span.context())
+            .map(move |res| (node_id, res))
+    });
+    let res: HashMap<u32, GetShardResponse> = join_all(reads).await
+        .into_iter()
+        .filter_map(|(node_id, res)| res.ok().map(|res| (node_id, res)))
+        .collect();
+
+    let mut original_shards: HashMap<usize, Vec<u8>> = HashMap::new();
+    let mut recovery_shards: HashMap<usize, Vec<u8>> = HashMap::new();
+
+    let owner_node_id = get_owner_node_id(location_id.clone());
+
+    if let Some(location_stats) = res.get(&owner_node_id).and_then(|res| res.location_stats.clone()) {
+        return Ok(EnrichedLocationStats {
+            id: location_stats.id,
+            seismic_activity: location_stats.seismic_activity,
+            temperature_c: location_stats.temperature_c,
+            radiation_level: location_stats.radiation_level,
+            modification_count: location_stats.modification_count,
+        });
+    }
+
+    let mut shard_count = 0;
+
+
+    for i in 0..6usize {
+        let node_id = shard_node_id(owner_node_id, i);
+        if let Some(shard) = res.get(&node_id).and_then(|res| res.shard.clone()) {
+            shard_count += 1;
+            if i >= 4 {
+                recovery_shards.insert(i-4, shard);
+            } else {
+                original_shards.insert(i, shard);
+            }
+      
+        }
+    }
+
+    span.set_attribute("shards.available", shard_count);
+    if shard_count == 0 {
+        return Err(ShardError::NotFoundError(format!("Location {} not found", location_id)));
+    }
+    if shard_count < REQUIRED_SHARDS {
+        return Err(ShardError::Unavailable(format!("Not enough shards: {} of {}", shard_count, REQUIRED_SHARDS)));
+    }
+
+    let restored = reed_solomon_simd::decode(
+        4, 2, original_shards.iter()
+        .map(|(k, v)| (*k, v.clone())), 
+        recovery_shards
+        .iter()
+        .map(|(k, v)| (*k, v.clone()))
+    ).map_err(|err| ShardError::DecodingError(err.to_string()))?;
+
+    let mut shards: [Vec<u8>; 4] = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+    for i in 0..4 {
+        if let Some(data) = original_shards.get(&i) {
+            shards[i] = data.clone();
+        } else if let Some(data) = restored.get(&i) {
+            shards[i] = data.clone();
+        } else {
+            error!("Failed to restore shard {}", i);
+            return Err(ShardError::DecodingError(format!("Failed to restore shard {}", i)));
+        }
+    }
+
+    EnrichedLocationStats::from_shards(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::Actor;
+    use crate::feed::{ChangeFeed, Close, Subscribe};
+
+    #[actix_rt::test]
+    async fn test_dropped_local_subscription_ends_with_error() {
+        let feed = ChangeFeed::new().start();
+        let filter = WatchFilter { prefixes: vec!["loc".to_owned()], ..WatchFilter::default() };
+        let subscription = feed.send(Subscribe(filter)).await.unwrap().unwrap();
+        feed.send(Close).await.unwrap();
+
+        let changes: Vec<_> = local_changes(3, subscription).collect().await;
+        assert!(matches!(changes.as_slice(), [Err(ShardError::Unavailable(_))]));
+    }
+}
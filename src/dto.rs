@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use uuid::{uuid, Uuid};
 
@@ -18,6 +19,15 @@ impl LocationStats {
             radiation_level: req.radiation_level
         }
     }
+
+    pub fn from_proto(stats: rs::LocationStats) -> Self {
+        LocationStats {
+            id: stats.id,
+            seismic_activity: stats.seismic_activity,
+            temperature_c: stats.temperature_c,
+            radiation_level: stats.radiation_level,
+        }
+    }
 }
 
 
@@ -53,15 +63,207 @@ impl ExtendedLocationStats {
     }
 }
 
+// Body of PATCH /{location_id}, absent fields keep their stored value
+#[derive(Deserialize, Clone, Default)]
+pub struct LocationPatch {
+    pub id: Option<String>,
+    pub seismic_activity: Option<f64>,
+    pub temperature_c: Option<f64>,
+    pub radiation_level: Option<f64>,
+}
+
+impl LocationPatch {
+    pub fn from_proto(req: &rs::RoutePatchRequest) -> Self {
+        LocationPatch {
+            id: req.id.clone(),
+            seismic_activity: req.seismic_activity,
+            temperature_c: req.temperature_c,
+            radiation_level: req.radiation_level,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.id.is_none() && self.seismic_activity.is_none() && self.temperature_c.is_none() && self.radiation_level.is_none()
+    }
+
+    pub fn apply(&self, base: &LocationStats) -> LocationStats {
+        LocationStats {
+            id: self.id.clone().unwrap_or_else(|| base.id.clone()),
+            seismic_activity: self.seismic_activity.unwrap_or(base.seismic_activity),
+            temperature_c: self.temperature_c.unwrap_or(base.temperature_c),
+            radiation_level: self.radiation_level.unwrap_or(base.radiation_level),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BatchPutItem {
+    pub location_id: String,
+    pub stats: LocationStats,
+    #[serde(default)]
+    pub expected_modification_count: Option<i64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BatchItemResult {
+    pub location_id: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl BatchItemResult {
+    pub fn from_result(location_id: String, success: StatusCode, result: Result<(), ShardError>) -> Self {
+        match result {
+            Ok(()) => BatchItemResult { location_id, status: success.as_u16(), error: None },
+            Err(err) => BatchItemResult { location_id, status: err.status_code().as_u16(), error: Some(err.body()) },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BatchGetRequest {
+    pub location_ids: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BatchGetResult {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<EnrichedLocationStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl BatchGetResult {
+    pub fn from_result(result: Result<EnrichedLocationStats, ShardError>) -> Self {
+        match result {
+            Ok(value) => BatchGetResult { status: StatusCode::OK.as_u16(), value: Some(value), error: None },
+            Err(err) => BatchGetResult { status: err.status_code().as_u16(), value: None, error: Some(err.body()) },
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct QueryMatch {
+    pub location_id: String,
+    pub stats: EnrichedLocationStats,
+}
+
+impl QueryMatch {
+    pub fn to_proto(&self) -> rs::QueryMatch {
+        rs::QueryMatch {
+            location_id: self.location_id.clone(),
+            location_stats: Some(self.stats.to_proto()),
+        }
+    }
+
+    pub fn from_proto(m: rs::QueryMatch) -> Result<Self, ShardError> {
+        let stats = m.location_stats
+            .ok_or_else(|| ShardError::RpcError(format!("Match {} has no stats", m.location_id)))?;
+        Ok(QueryMatch { location_id: m.location_id, stats: EnrichedLocationStats::from_proto(stats) })
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct QueryPage {
+    pub matches: Vec<QueryMatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_nodes: Vec<u32>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct AggregateReport {
+    pub total: GroupSummary,
+    // Present when grouping by a location id prefix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<BTreeMap<String, GroupSummary>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_nodes: Vec<u32>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct LocationPage {
+    pub location_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    // Nodes that could not be listed, their locations are missing from this page
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_nodes: Vec<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PeerStatus {
+    pub node_id: u32,
+    pub endpoint: String,
+    pub is_self: bool,
+    pub connected: bool,
+    pub reset: bool,
+    pub last_reset_ms_ago: Option<u64>,
+    pub recent_rpc_errors: usize,
+    pub reachable: bool,
+    #[serde(default)]
+    pub serving: bool,
+    #[serde(default)]
+    pub compatibility: Compatibility,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PoolStats {
+    pub pool_size: usize,
+    pub locations: usize,
+    #[serde(default)]
+    pub owned: usize,
+    pub is_refreshing: bool,
+    pub warmed_up: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct NodeStatus {
+    pub node_id: u32,
+    pub endpoints: BTreeMap<u32, String>,
+    pub peers: Vec<PeerStatus>,
+    pub root_actors: Vec<PoolStats>,
+    #[serde(default)]
+    pub rejected_rpcs: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub draining: bool,
+    pub warmed_up: bool,
+    pub reachable_peers: usize,
+    pub required_peers: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct NodeReport {
+    pub node_id: u32,
+    pub endpoint: String,
+    pub status: Option<NodeStatus>,
+    pub error: Option<String>,
+}
+
 
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt::{self, write};
 
-use crate::rs::rs::RouteWriteRequest;
+use actix::MailboxError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use tonic::{Code, Status};
+
+use crate::rs::rs::{self, RouteWriteRequest};
+use crate::aggregate::GroupSummary;
+use crate::protocol::Compatibility;
+use crate::validation::FieldError;
 
-// Custom error type for shard operations
-#[derive(Debug)]
+// Custom error type for shard operations, also the error type of the request path
+#[derive(Debug, Clone)]
 pub enum ShardError {
     EncodingError(String),
     DecodingError(String),
@@ -69,7 +271,50 @@ pub enum ShardError {
     RpcError(String),
     ChannelError(String),
     ActixError(String),
-    NotFoundError(String)
+    NotFoundError(String),
+    InvalidInput(String),
+    ValidationError(Vec<FieldError>),
+    Unavailable(String),
+    PreconditionFailed(String),
+    Unauthenticated(String),
+    PermissionDenied(String),
+    // Reached a node that isn't responsible for the location
+    Misrouted(String),
+    // The request's time budget ran out before a peer answered
+    DeadlineExceeded(String),
+}
+
+impl ShardError {
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+            fields: match self {
+                ShardError::ValidationError(fields) => fields.clone(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ShardError::EncodingError(_) => "encoding_error",
+            ShardError::DecodingError(_) => "decoding_error",
+            ShardError::InvalidShard(_) => "invalid_shard",
+            ShardError::RpcError(_) => "rpc_error",
+            ShardError::ChannelError(_) => "channel_error",
+            ShardError::ActixError(_) => "actix_error",
+            ShardError::NotFoundError(_) => "not_found",
+            ShardError::InvalidInput(_) => "invalid_input",
+            ShardError::ValidationError(_) => "validation_error",
+            ShardError::Unavailable(_) => "unavailable",
+            ShardError::PreconditionFailed(_) => "precondition_failed",
+            ShardError::Unauthenticated(_) => "unauthenticated",
+            ShardError::PermissionDenied(_) => "permission_denied",
+            ShardError::Misrouted(_) => "misrouted",
+            ShardError::DeadlineExceeded(_) => "deadline_exceeded",
+        }
+    }
 }
 
 impl fmt::Display for ShardError {
@@ -81,13 +326,188 @@ impl fmt::Display for ShardError {
             ShardError::RpcError(msg) => write!(f, "Rpc Error: {}", msg),
             ShardError::ChannelError(msg) => write!(f, "Channel Error: {}", msg),
             ShardError::ActixError(msg) => write!(f, "Actix Error: {}", msg),
-            ShardError::NotFoundError(msg) => write!(f, "Shard Not found Error: {}", msg)
+            ShardError::NotFoundError(msg) => write!(f, "Not found: {}", msg),
+            ShardError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
+            ShardError::ValidationError(fields) => {
+                let details: Vec<String> = fields.iter()
+                    .map(|field| format!("{} {}", field.field, field.message))
+                    .collect();
+                write!(f, "Validation failed: {}", details.join("; "))
+            }
+            ShardError::Unavailable(msg) => write!(f, "Unavailable: {}", msg),
+            ShardError::PreconditionFailed(msg) => write!(f, "Precondition failed: {}", msg),
+            ShardError::Unauthenticated(msg) => write!(f, "Unauthenticated: {}", msg),
+            ShardError::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
+            ShardError::Misrouted(msg) => write!(f, "Misrouted: {}", msg),
+            ShardError::DeadlineExceeded(msg) => write!(f, "Deadline exceeded: {}", msg),
         }
     }
 }
 
 impl Error for ShardError {}
 
+impl From<MailboxError> for ShardError {
+    fn from(err: MailboxError) -> Self {
+        ShardError::ActixError(err.to_string())
+    }
+}
+
+impl From<Status> for ShardError {
+    fn from(status: Status) -> Self {
+        let msg = status.message().to_owned();
+        match status.code() {
+            Code::NotFound => ShardError::NotFoundError(msg),
+            Code::InvalidArgument => ShardError::InvalidInput(msg),
+            Code::Unavailable => ShardError::Unavailable(msg),
+            Code::FailedPrecondition => ShardError::PreconditionFailed(msg),
+            Code::Unauthenticated => ShardError::Unauthenticated(msg),
+            Code::PermissionDenied => ShardError::PermissionDenied(msg),
+            Code::OutOfRange => ShardError::Misrouted(msg),
+            Code::DeadlineExceeded => ShardError::DeadlineExceeded(msg),
+            _ => ShardError::RpcError(status.to_string()),
+        }
+    }
+}
+
+impl From<ShardError> for Status {
+    fn from(err: ShardError) -> Self {
+        let msg = err.to_string();
+        match err {
+            ShardError::NotFoundError(_) => Status::not_found(msg),
+            ShardError::InvalidInput(_) | ShardError::ValidationError(_) => Status::invalid_argument(msg),
+            ShardError::Unavailable(_) | ShardError::ChannelError(_) => Status::unavailable(msg),
+            ShardError::RpcError(_) => Status::aborted(msg),
+            ShardError::PreconditionFailed(_) => Status::failed_precondition(msg),
+            ShardError::Unauthenticated(_) => Status::unauthenticated(msg),
+            ShardError::PermissionDenied(_) => Status::permission_denied(msg),
+            ShardError::Misrouted(_) => Status::out_of_range(msg),
+            ShardError::DeadlineExceeded(_) => Status::deadline_exceeded(msg),
+            ShardError::EncodingError(_)
+            | ShardError::DecodingError(_)
+            | ShardError::InvalidShard(_)
+            | ShardError::ActixError(_) => Status::internal(msg),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct ErrorBody {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+impl ResponseError for ShardError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ShardError::NotFoundError(_) => StatusCode::NOT_FOUND,
+            ShardError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            ShardError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ShardError::Unavailable(_) | ShardError::ChannelError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ShardError::RpcError(_) => StatusCode::BAD_GATEWAY,
+            ShardError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ShardError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            ShardError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            ShardError::Misrouted(_) => StatusCode::MISDIRECTED_REQUEST,
+            ShardError::DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            ShardError::EncodingError(_)
+            | ShardError::DecodingError(_)
+            | ShardError::InvalidShard(_)
+            | ShardError::ActixError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let ShardError::Unauthenticated(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(self.body())
+    }
+}
+
+// ETags are the quoted modification_count of a location
+pub fn etag(modification_count: i64) -> String {
+    format!("\"{}\"", modification_count)
+}
+
+// The condition a conditional write puts on the stored record, from If-Match.
+// `*` only needs the location to exist; a list of ETags needs it to be at one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IfMatch {
+    Exists,
+    AnyOf(Vec<i64>),
+}
+
+impl IfMatch {
+    pub fn from_count(modification_count: Option<i64>) -> Option<Self> {
+        modification_count.map(|count| IfMatch::AnyOf(vec![count]))
+    }
+
+    // The expected_modification_count, expected_modification_counts and expect_exists
+    // fields; a single ETag still goes in the field older peers read
+    pub fn to_proto(condition: Option<&IfMatch>) -> (Option<i64>, Vec<i64>, bool) {
+        match condition {
+            None => (None, Vec::new(), false),
+            Some(IfMatch::Exists) => (None, Vec::new(), true),
+            Some(IfMatch::AnyOf(counts)) if counts.len() == 1 => (Some(counts[0]), Vec::new(), false),
+            Some(IfMatch::AnyOf(counts)) => (None, counts.clone(), false),
+        }
+    }
+
+    pub fn from_proto(count: Option<i64>, counts: Vec<i64>, exists: bool) -> Option<Self> {
+        if exists {
+            return Some(IfMatch::Exists);
+        }
+        let counts: Vec<i64> = count.into_iter().chain(counts).collect();
+        (!counts.is_empty()).then_some(IfMatch::AnyOf(counts))
+    }
+
+    pub fn check(&self, location_id: &str, exists: bool, modification_count: i64) -> Result<(), ShardError> {
+        if !exists {
+            return Err(ShardError::PreconditionFailed(format!("{} does not exist", location_id)));
+        }
+        match self {
+            IfMatch::AnyOf(counts) if !counts.contains(&modification_count) => Err(ShardError::PreconditionFailed(format!(
+                "{} is at modification_count {}, expected one of {:?}", location_id, modification_count, counts
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Parses an If-Match value: `*` or a comma-separated list of ETags. If-Match uses the
+// strong comparison, so a weak ETag never matches; a list of only weak ones always fails.
+pub fn parse_if_match(value: &str) -> Result<IfMatch, ShardError> {
+    if value.trim() == "*" {
+        return Ok(IfMatch::Exists);
+    }
+    let mut counts = Vec::new();
+    let mut weak = false;
+    for tag in value.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+        let (is_weak, quoted) = match tag.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, tag),
+        };
+        let count = quoted.strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+            .and_then(|v| v.parse::<i64>().ok())
+            .ok_or_else(|| ShardError::InvalidInput(format!("If-Match must list quoted modification counts, got {}", tag)))?;
+        if is_weak {
+            weak = true;
+        } else {
+            counts.push(count);
+        }
+    }
+    if counts.is_empty() && weak {
+        return Err(ShardError::PreconditionFailed("If-Match only lists weak ETags".to_owned()));
+    }
+    if counts.is_empty() {
+        return Err(ShardError::InvalidInput("If-Match is empty".to_owned()));
+    }
+    Ok(IfMatch::AnyOf(counts))
+}
+
 // Constants for our encoding
 const ID_SIZE: usize = 16; // uuid
 const TOTAL_SIZE: usize = ID_SIZE + 8 + 8 + 8 + 8; // id + 4 fields (8 bytes each)
@@ -128,12 +548,33 @@ impl EnrichedLocationStats {
         }
     }
 
+    pub fn from_proto(stats: rs::EnrichedLocationStats) -> Self {
+        EnrichedLocationStats {
+            id: stats.id,
+            modification_count: stats.modification_count,
+            seismic_activity: stats.seismic_activity,
+            temperature_c: stats.temperature_c,
+            radiation_level: stats.radiation_level,
+        }
+    }
+
+    pub fn to_proto(&self) -> rs::EnrichedLocationStats {
+        rs::EnrichedLocationStats {
+            id: self.id.clone(),
+            modification_count: self.modification_count,
+            seismic_activity: self.seismic_activity,
+            temperature_c: self.temperature_c,
+            radiation_level: self.radiation_level,
+        }
+    }
+
     // Encode the stats into 4 equal-sized shards
     pub fn to_shards(&self) -> Result<[Vec<u8>; 4], ShardError> {
         // Create a buffer big enough to hold all data
         let mut buffer = vec![0u8; TOTAL_SIZE];
 
-        let uuid = Uuid::parse_str(&self.id).unwrap();
+        let uuid = Uuid::parse_str(&self.id)
+            .map_err(|err| ShardError::InvalidInput(format!("id '{}' is not a UUID: {}", self.id, err)))?;
     
 
         // Encode numeric fields (using little-endian byte order)
@@ -188,7 +629,9 @@ impl EnrichedLocationStats {
         // Extract ID (terminating at null or $ padding)
         let id_bytes = &buffer[0..ID_SIZE];
 
-        let id = Uuid::from_slice(id_bytes).unwrap().to_string();
+        let id = Uuid::from_slice(id_bytes)
+            .map_err(|err| ShardError::DecodingError(format!("Failed to decode id: {}", err)))?
+            .to_string();
 
         // Extract numeric fields
         let modification_count =
@@ -348,6 +791,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_to_shards_rejects_non_uuid_id() {
+        let stats = EnrichedLocationStats::new("not-a-uuid".to_string(), 1, 0.0, 0.0, 0.0).unwrap();
+
+        match stats.to_shards() {
+            Err(ShardError::InvalidInput(msg)) => assert!(msg.contains("not-a-uuid")),
+            _ => panic!("Expected InvalidInput"),
+        }
+    }
+
+    #[test]
+    fn test_error_status_mapping() {
+        assert_eq!(ShardError::NotFoundError("x".into()).status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(ShardError::InvalidInput("x".into()).status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(ShardError::Unavailable("x".into()).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ShardError::DecodingError("x".into()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert_eq!(Status::from(ShardError::NotFoundError("x".into())).code(), Code::NotFound);
+        assert_eq!(Status::from(ShardError::InvalidInput("x".into())).code(), Code::InvalidArgument);
+        assert!(matches!(ShardError::from(Status::unavailable("down")), ShardError::Unavailable(_)));
+
+        assert_eq!(ShardError::PreconditionFailed("x".into()).status_code(), StatusCode::PRECONDITION_FAILED);
+        assert!(matches!(
+            ShardError::from(Status::from(ShardError::PreconditionFailed("x".into()))),
+            ShardError::PreconditionFailed(_)
+        ));
+
+        assert_eq!(ShardError::DeadlineExceeded("x".into()).status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(matches!(ShardError::from(Status::deadline_exceeded("slow")), ShardError::DeadlineExceeded(_)));
+    }
+
+    #[test]
+    fn test_patch_keeps_absent_fields() {
+        let base = LocationStats {
+            id: "bac32c52-bb64-476d-a36d-91069bbd8a5e".to_string(),
+            seismic_activity: 2.5,
+            temperature_c: 25.5,
+            radiation_level: 0.001,
+        };
+        let patch = LocationPatch { temperature_c: Some(-4.0), ..LocationPatch::default() };
+
+        let merged = patch.apply(&base);
+        assert_eq!(merged.id, base.id);
+        assert_eq!(merged.seismic_activity, 2.5);
+        assert_eq!(merged.temperature_c, -4.0);
+        assert_eq!(merged.radiation_level, 0.001);
+        assert!(!patch.is_empty());
+        assert!(LocationPatch::default().is_empty());
+    }
+
+    #[test]
+    fn test_if_match_roundtrip() {
+        assert_eq!(parse_if_match(&etag(7)).unwrap(), IfMatch::AnyOf(vec![7]));
+        assert!(matches!(parse_if_match("W/\"3\""), Err(ShardError::PreconditionFailed(_))));
+        assert_eq!(parse_if_match("W/\"3\", \"5\"").unwrap(), IfMatch::AnyOf(vec![5]));
+        assert!(matches!(parse_if_match("12"), Err(ShardError::InvalidInput(_))));
+        assert_eq!(parse_if_match(" * ").unwrap(), IfMatch::Exists);
+        assert_eq!(parse_if_match("\"4\", \"9\"").unwrap(), IfMatch::AnyOf(vec![4, 9]));
+        assert!(matches!(parse_if_match("\"4\", x"), Err(ShardError::InvalidInput(_))));
+        assert!(matches!(parse_if_match(" , "), Err(ShardError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_if_match_check() {
+        assert!(IfMatch::Exists.check("loc", true, 3).is_ok());
+        assert!(matches!(IfMatch::Exists.check("loc", false, 3), Err(ShardError::PreconditionFailed(_))));
+        assert!(IfMatch::AnyOf(vec![2, 3]).check("loc", true, 3).is_ok());
+        assert!(IfMatch::AnyOf(vec![2, 4]).check("loc", true, 3).is_err());
+
+        for condition in [IfMatch::Exists, IfMatch::AnyOf(vec![5]), IfMatch::AnyOf(vec![5, 6])] {
+            let (count, counts, exists) = IfMatch::to_proto(Some(&condition));
+            assert_eq!(IfMatch::from_proto(count, counts, exists), Some(condition));
+        }
+        assert_eq!(IfMatch::from_proto(None, Vec::new(), false), None);
+    }
+
     #[test]
     fn test_from_shards_corrupted_data() {
         // Create valid stats and encode to shards
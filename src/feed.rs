@@ -0,0 +1,422 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::OnceLock;
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::dto::{EnrichedLocationStats, ShardError};
+use crate::rs::rs;
+use crate::util::get_owner_node_id;
+
+// Changes a subscriber may fall behind by before it gets disconnected
+const SUBSCRIBER_BUFFER: usize = 256;
+// Recent changes kept so a reconnecting watcher can resume where it left off
+const HISTORY_SIZE: usize = 4096;
+// Locations whose last evicted change is remembered; older ones are forgotten and
+// watchers resuming on them have to re-read
+const HORIZON_SIZE: usize = 16 * HISTORY_SIZE;
+
+static FEED: OnceLock<Addr<ChangeFeed>> = OnceLock::new();
+
+// A committed write or delete, published by the owner's LocationActor. A delete
+// carries the last record, with the modification_count the delete took.
+#[derive(Serialize, Clone)]
+pub struct LocationChange {
+    pub location_id: String,
+    pub stats: EnrichedLocationStats,
+    pub deleted: bool,
+}
+
+impl LocationChange {
+    pub fn to_proto(&self) -> rs::LocationChange {
+        rs::LocationChange {
+            location_id: self.location_id.clone(),
+            location_stats: Some(self.stats.to_proto()),
+            deleted: self.deleted,
+        }
+    }
+
+    pub fn from_proto(change: rs::LocationChange) -> Result<Self, ShardError> {
+        let stats = change.location_stats
+            .ok_or_else(|| ShardError::RpcError(format!("Change to {} has no stats", change.location_id)))?;
+        Ok(LocationChange {
+            location_id: change.location_id,
+            stats: EnrichedLocationStats::from_proto(stats),
+            deleted: change.deleted,
+        })
+    }
+}
+
+// Which locations a subscriber watches: explicit ids, prefixes, or both.
+// `resume_from` maps a location to the last modification_count the watcher saw;
+// newer changes still in the history are replayed before live ones.
+#[derive(Clone, Default, Debug)]
+pub struct WatchFilter {
+    pub location_ids: HashSet<String>,
+    pub prefixes: Vec<String>,
+    pub resume_from: HashMap<String, i64>,
+}
+
+impl WatchFilter {
+    pub fn from_proto(req: rs::SubscribeRequest) -> Self {
+        WatchFilter {
+            location_ids: req.location_ids.into_iter().collect(),
+            prefixes: req.prefixes.into_iter().filter(|prefix| !prefix.is_empty()).collect(),
+            resume_from: req.resume_from,
+        }
+    }
+
+    pub fn to_proto(&self) -> rs::SubscribeRequest {
+        rs::SubscribeRequest {
+            location_ids: self.location_ids.iter().cloned().collect(),
+            prefixes: self.prefixes.clone(),
+            resume_from: self.resume_from.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.location_ids.is_empty() && self.prefixes.is_empty()
+    }
+
+    pub fn matches(&self, location_id: &str) -> bool {
+        self.location_ids.contains(location_id)
+            || self.prefixes.iter().any(|prefix| location_id.starts_with(prefix.as_str()))
+    }
+
+    // Nodes that can commit a matching location, None when any node can (prefix watches)
+    pub fn owners(&self) -> Option<HashSet<u32>> {
+        if !self.prefixes.is_empty() {
+            return None;
+        }
+        Some(self.location_ids.iter().map(|id| get_owner_node_id(id.clone())).collect())
+    }
+
+    // The same watch with only the resume points of locations `node_id` owns, the
+    // only ones its feed can vouch for
+    pub fn for_owner(&self, node_id: u32) -> Self {
+        WatchFilter {
+            resume_from: self.resume_from.iter()
+                .filter(|(location_id, _)| get_owner_node_id((*location_id).clone()) == node_id)
+                .map(|(location_id, seen)| (location_id.clone(), *seen))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    fn replays(&self, change: &LocationChange) -> bool {
+        self.matches(&change.location_id)
+            && self.resume_from.get(&change.location_id).map_or(false, |seen| change.stats.modification_count > *seen)
+    }
+}
+
+// Buffered changes to replay, followed by live ones
+pub struct Subscription {
+    replay: Vec<LocationChange>,
+    rx: mpsc::Receiver<LocationChange>,
+}
+
+struct Subscriber {
+    filter: WatchFilter,
+    tx: mpsc::Sender<LocationChange>,
+}
+
+// Fans committed writes out to local watchers and to peers subscribed over gRPC
+pub struct ChangeFeed {
+    next_id: u64,
+    subscribers: HashMap<u64, Subscriber>,
+    history: VecDeque<LocationChange>,
+    // Highest modification_count per location that fell out of the history
+    horizon: HashMap<String, i64>,
+    // Locations in the horizon, oldest first
+    horizon_order: VecDeque<String>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        ChangeFeed {
+            next_id: 0,
+            subscribers: HashMap::new(),
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            horizon: HashMap::new(),
+            horizon_order: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, change: LocationChange) {
+        if self.history.len() == HISTORY_SIZE {
+            if let Some(evicted) = self.history.pop_front() {
+                self.remember_evicted(evicted);
+            }
+        }
+        self.history.push_back(change);
+    }
+
+    fn remember_evicted(&mut self, evicted: LocationChange) {
+        if self.horizon.insert(evicted.location_id.clone(), evicted.stats.modification_count).is_none() {
+            self.horizon_order.push_back(evicted.location_id);
+        }
+        if self.horizon_order.len() > HORIZON_SIZE {
+            if let Some(forgotten) = self.horizon_order.pop_front() {
+                self.horizon.remove(&forgotten);
+            }
+        }
+    }
+
+    // A watcher can only resume from a change this feed saw, retained or evicted, with
+    // nothing dropped after it. Anything else, as after a restart, makes it re-read.
+    fn replay(&self, filter: &WatchFilter) -> Result<Vec<LocationChange>, ShardError> {
+        for (location_id, seen) in &filter.resume_from {
+            let dropped = self.horizon.get(location_id);
+            if let Some(dropped) = dropped.filter(|dropped| **dropped > *seen) {
+                return Err(ShardError::PreconditionFailed(format!(
+                    "Changes to {} up to modification_count {} are no longer available to resume from {}", location_id, dropped, seen
+                )));
+            }
+            let known = dropped.is_some() || self.history.iter()
+                .any(|change| change.location_id == *location_id && change.stats.modification_count <= *seen);
+            if !known {
+                return Err(ShardError::PreconditionFailed(format!(
+                    "No changes to {} are known from modification_count {}, it has to be re-read", location_id, seen
+                )));
+            }
+        }
+        Ok(self.history.iter().filter(|change| filter.replays(change)).cloned().collect())
+    }
+}
+
+impl Actor for ChangeFeed {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("ChangeFeed started");
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish(pub LocationChange);
+
+#[derive(Message)]
+#[rtype(result = "Result<Subscription, ShardError>")]
+pub struct Subscribe(pub WatchFilter);
+
+// Drops every subscriber so their streams end, used on shutdown
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Close;
+
+impl Handler<Publish> for ChangeFeed {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _ctx: &mut Context<Self>) -> Self::Result {
+        let change = msg.0;
+        self.subscribers.retain(|id, subscriber| {
+            if !subscriber.filter.matches(&change.location_id) {
+                return true;
+            }
+            match subscriber.tx.try_send(change.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Subscriber {} fell {} changes behind, disconnecting it", id, SUBSCRIBER_BUFFER);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+        self.record(change);
+    }
+}
+
+impl Handler<Subscribe> for ChangeFeed {
+    type Result = Result<Subscription, ShardError>;
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) -> Self::Result {
+        let replay = self.replay(&msg.0)?;
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.next_id += 1;
+        self.subscribers.insert(self.next_id, Subscriber { filter: msg.0, tx });
+        Ok(Subscription { replay, rx })
+    }
+}
+
+impl Handler<Close> for ChangeFeed {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Close, _ctx: &mut Context<Self>) -> Self::Result {
+        info!("Closing {} change subscriptions", self.subscribers.len());
+        self.subscribers.clear();
+    }
+}
+
+pub fn init() {
+    if FEED.set(ChangeFeed::new().start()).is_err() {
+        error!("Change feed already initialised");
+    }
+}
+
+pub fn publish(location_id: String, stats: EnrichedLocationStats) {
+    if let Some(feed) = FEED.get() {
+        feed.do_send(Publish(LocationChange { location_id, stats, deleted: false }));
+    }
+}
+
+pub fn publish_delete(location_id: String, last: EnrichedLocationStats) {
+    if let Some(feed) = FEED.get() {
+        feed.do_send(Publish(LocationChange { location_id, stats: last, deleted: true }));
+    }
+}
+
+pub async fn subscribe(filter: WatchFilter) -> Result<Subscription, ShardError> {
+    let feed = FEED.get().ok_or_else(|| ShardError::Unavailable("change feed is not running".to_owned()))?;
+    feed.send(Subscribe(filter)).await?
+}
+
+pub async fn close() {
+    if let Some(feed) = FEED.get() {
+        if let Err(err) = feed.send(Close).await {
+            error!("Failed to close change subscriptions: {}", err);
+        }
+    }
+}
+
+pub fn changes(subscription: Subscription) -> impl Stream<Item = LocationChange> {
+    let live = stream::unfold(subscription.rx, |mut rx| async move { rx.recv().await.map(|change| (change, rx)) });
+    stream::iter(subscription.replay).chain(live)
+}
+
+// One Server-Sent Events message
+pub fn sse_event(event: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_else(|err| format!("\"{}\"", err));
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::stats_at;
+
+    fn change(location_id: &str, modification_count: i64) -> LocationChange {
+        LocationChange {
+            location_id: location_id.to_owned(),
+            stats: stats_at(modification_count, 2.5, 25.5, 0.001),
+            deleted: false,
+        }
+    }
+
+    fn watching(location_id: &str) -> WatchFilter {
+        WatchFilter { location_ids: HashSet::from([location_id.to_owned()]), ..WatchFilter::default() }
+    }
+
+    #[test]
+    fn test_filter_matches_ids_and_prefix() {
+        let filter = WatchFilter {
+            location_ids: HashSet::from(["loc-1".to_owned()]),
+            prefixes: vec!["site-a/".to_owned(), "site-b/".to_owned()],
+            ..WatchFilter::default()
+        };
+
+        assert!(filter.matches("loc-1"));
+        assert!(filter.matches("site-a/42"));
+        assert!(filter.matches("site-b/7"));
+        assert!(!filter.matches("loc-2"));
+        assert!(filter.owners().is_none());
+        assert!(WatchFilter::default().is_empty());
+
+        let resuming = WatchFilter { resume_from: HashMap::from([("loc-1".to_owned(), 3), ("loc-2".to_owned(), 4)]), ..filter };
+        let owner = get_owner_node_id("loc-1".to_owned());
+        let narrowed = resuming.for_owner(owner);
+        assert_eq!(narrowed.resume_from.get("loc-1"), Some(&3));
+        assert_eq!(narrowed.resume_from.contains_key("loc-2"), get_owner_node_id("loc-2".to_owned()) == owner);
+        assert_eq!(narrowed.prefixes, resuming.prefixes);
+    }
+
+    #[actix_rt::test]
+    async fn test_publish_reaches_matching_subscribers() {
+        let feed = ChangeFeed::new().start();
+        let mut watching = feed.send(Subscribe(watching("loc-1"))).await.unwrap().unwrap().rx;
+        let closed = feed.send(Subscribe(WatchFilter { prefixes: vec!["loc".to_owned()], ..WatchFilter::default() })).await.unwrap();
+        drop(closed);
+
+        feed.send(Publish(change("loc-2", 1))).await.unwrap();
+        feed.send(Publish(change("loc-1", 1))).await.unwrap();
+
+        assert_eq!(watching.recv().await.unwrap().location_id, "loc-1");
+        assert!(watching.try_recv().is_err());
+
+        feed.send(Close).await.unwrap();
+        assert!(watching.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_resume_replays_newer_changes() {
+        let mut feed = ChangeFeed::new();
+        for count in 1..=3 {
+            feed.record(change("loc-1", count));
+            feed.record(change("loc-2", count));
+        }
+
+        let mut filter = watching("loc-1");
+        filter.resume_from.insert("loc-1".to_owned(), 1);
+        let counts: Vec<i64> = feed.replay(&filter).unwrap().iter().map(|c| c.stats.modification_count).collect();
+        assert_eq!(counts, vec![2, 3]);
+
+        assert!(feed.replay(&watching("loc-1")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resume_past_history_fails() {
+        let mut feed = ChangeFeed::new();
+        for count in 1..=(HISTORY_SIZE as i64 + 2) {
+            feed.record(change("loc-1", count));
+        }
+
+        let mut filter = watching("loc-1");
+        filter.resume_from.insert("loc-1".to_owned(), 1);
+        assert!(matches!(feed.replay(&filter), Err(ShardError::PreconditionFailed(_))));
+
+        filter.resume_from.insert("loc-1".to_owned(), 2);
+        assert_eq!(feed.replay(&filter).unwrap().len(), HISTORY_SIZE);
+    }
+
+    #[test]
+    fn test_resume_before_known_changes_fails() {
+        let mut feed = ChangeFeed::new();
+        let mut filter = watching("loc-1");
+        filter.resume_from.insert("loc-1".to_owned(), 5);
+        assert!(matches!(feed.replay(&filter), Err(ShardError::PreconditionFailed(_))));
+
+        // Change 6 may have been committed before this feed started
+        feed.record(change("loc-1", 7));
+        assert!(matches!(feed.replay(&filter), Err(ShardError::PreconditionFailed(_))));
+
+        filter.resume_from.insert("loc-1".to_owned(), 7);
+        assert!(feed.replay(&filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_horizon_is_bounded() {
+        let mut feed = ChangeFeed::new();
+        for i in 0..(HISTORY_SIZE + HORIZON_SIZE + 10) {
+            feed.record(change(&format!("loc-{}", i), 1));
+        }
+        assert_eq!(feed.horizon.len(), HORIZON_SIZE);
+        assert_eq!(feed.horizon_order.len(), HORIZON_SIZE);
+
+        let mut filter = watching("loc-0");
+        filter.resume_from.insert("loc-0".to_owned(), 1);
+        assert!(matches!(feed.replay(&filter), Err(ShardError::PreconditionFailed(_))));
+        filter.resume_from = HashMap::from([("loc-20".to_owned(), 1)]);
+        assert!(feed.replay(&filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sse_event_format() {
+        let event = sse_event("change", &change("loc-1", 1));
+        let text = std::str::from_utf8(&event).unwrap();
+        assert!(text.starts_with("event: change\ndata: {\"location_id\":\"loc-1\""));
+        assert!(text.ends_with("\n\n"));
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+use actix::{Addr, MailboxError};
+use futures::future::try_join_all;
+use log::{error, info};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::conn_manager::{ChannelManager, GetPeerStatus};
+use crate::constants::REQUIRED_SHARDS;
+use crate::dto::{NodeStatus, Readiness};
+use crate::root_actor::{GetPoolStats, RootActor};
+use crate::sender;
+use crate::shutdown::Drain;
+
+// Names as registered with grpc.health.v1, "" is the node as a whole
+pub const RS_SERVICE: &str = "rs.Rs";
+pub const LOCATION_SERVICE: &str = "rs.LocationService";
+const HEALTH_REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+pub async fn node_status(current_node: u32, root_actor_pool: &[Addr<RootActor>], channel_manager: &Addr<ChannelManager>, endpoints: &[String]) -> Result<NodeStatus, MailboxError> {
+    let peers = channel_manager.send(GetPeerStatus).await?;
+    let root_actors = try_join_all(root_actor_pool.iter().map(|root_actor| root_actor.send(GetPoolStats))).await?;
+
+    Ok(NodeStatus {
+        node_id: current_node,
+        endpoints: endpoints.iter().cloned().enumerate().map(|(i, endpoint)| (i as u32, endpoint)).collect(),
+        peers,
+        root_actors,
+        rejected_rpcs: sender::rejections(),
+    })
+}
+
+pub fn readiness(status: &NodeStatus, draining: bool) -> Readiness {
+    let warmed_up = status.root_actors.iter().all(|pool| pool.warmed_up);
+    let reachable_peers = status.peers.iter()
+        .filter(|peer| !peer.is_self && peer.reachable && peer.serving && peer.compatibility.is_usable())
+        .count();
+    // This node holds a shard as well, so it needs one peer fewer than a read needs shards
+    let required_peers = REQUIRED_SHARDS - 1;
+
+    Readiness {
+        ready: !draining && warmed_up && reachable_peers >= required_peers,
+        draining,
+        warmed_up,
+        reachable_peers,
+        required_peers,
+    }
+}
+
+fn serving_status(serving: bool) -> ServingStatus {
+    if serving { ServingStatus::Serving } else { ServingStatus::NotServing }
+}
+
+// Peers route shards to us once our root actors are warmed up and answering, so
+// rs.Rs doesn't depend on how many other peers we see; that would let one
+// partition mark the whole cluster down. Clients additionally need enough
+// reachable peers and no drain in progress.
+pub async fn report(mut reporter: HealthReporter, current_node: u32, root_actor_pool: Vec<Addr<RootActor>>, channel_manager: Addr<ChannelManager>, endpoints: Vec<String>, drain: Drain) {
+    let mut interval = tokio::time::interval(HEALTH_REPORT_INTERVAL);
+    let mut last = None;
+    loop {
+        interval.tick().await;
+        let (storage, ready) = match node_status(current_node, &root_actor_pool, &channel_manager, &endpoints).await {
+            Ok(status) => {
+                let readiness = readiness(&status, drain.is_draining());
+                (readiness.warmed_up, readiness.ready)
+            }
+            Err(err) => {
+                error!("Failed to collect node status for health: {}", err);
+                (false, false)
+            }
+        };
+
+        if last != Some((storage, ready)) {
+            info!("Health: {} {:?}, {} {:?}", RS_SERVICE, serving_status(storage), LOCATION_SERVICE, serving_status(ready));
+            last = Some((storage, ready));
+        }
+        reporter.set_service_status(RS_SERVICE, serving_status(storage)).await;
+        reporter.set_service_status(LOCATION_SERVICE, serving_status(ready)).await;
+        reporter.set_service_status("", serving_status(ready)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dto::{PeerStatus, PoolStats};
+    use crate::protocol::Compatibility;
+
+    fn peer(node_id: u32, serving: bool) -> PeerStatus {
+        PeerStatus {
+            node_id,
+            endpoint: format!("127.0.0.1:{}", 8000 + node_id),
+            is_self: node_id == 0,
+            connected: true,
+            reset: false,
+            last_reset_ms_ago: None,
+            recent_rpc_errors: 0,
+            reachable: true,
+            serving,
+            compatibility: Compatibility::Compatible,
+        }
+    }
+
+    #[test]
+    fn test_readiness_skips_peers_not_serving() {
+        let mut status = NodeStatus {
+            node_id: 0,
+            endpoints: Default::default(),
+            peers: (0..6).map(|node_id| peer(node_id, true)).collect(),
+            root_actors: vec![PoolStats { pool_size: 1, locations: 0, owned: 0, is_refreshing: false, warmed_up: true }],
+            rejected_rpcs: Default::default(),
+        };
+        assert!(readiness(&status, false).ready);
+        assert!(!readiness(&status, true).ready);
+
+        // Three peers plus this node hold enough shards
+        status.peers[1].serving = false;
+        status.peers[2].serving = false;
+        let ready = readiness(&status, false);
+        assert_eq!(ready.reachable_peers, 3);
+        assert_eq!(ready.required_peers, 3);
+        assert!(ready.ready);
+
+        status.peers[3].serving = false;
+        let not_ready = readiness(&status, false);
+        assert_eq!(not_ready.reachable_peers, 2);
+        assert!(!not_ready.ready);
+    }
+}
@@ -4,7 +4,7 @@ use std::str::FromStr;
 use std::sync::Arc;
 use actix::fut::{ready, wrap_future};
 use actix::prelude::*;
-use futures::future::try_join_all;
+use futures::future::join_all;
 use futures::FutureExt;
 use log::{error, info};
 use tonic::transport::{Channel, Endpoint, Uri};
@@ -27,49 +27,54 @@ impl LocationActor {
         }
     }
 
-    async fn write(self: &Self, addr: Arc<Addr<ChannelManager>>, location_id: String, data: EnrichedLocationStats) -> Result<Vec<()>, ShardError>{
-        let channels_result = addr
-            .send(GetAllChannels {})
-            .await;
-
-        let (current_node, channels) = channels_result.unwrap().unwrap();
+    async fn write(self: &Self, addr: Arc<Addr<ChannelManager>>, location_id: String, data: EnrichedLocationStats, trace: TraceContext) -> Result<Vec<()>, ShardError>{
+        let mut span = Span::start("location.write", Some(&trace), SpanKind::Internal);
+        span.set_attribute("location_id", &location_id);
+        span.set_attribute("modification_count", data.modification_count);
 
+        let holders = addr.send(GetShardHolders).await?;
 
         let shards = data.to_shards()?;
 
         
         // Generate Reed-Solomon recovery shards
-        let recovery_shards = actix_web::rt::task::spawn_blocking(move|| {
-            [shards.to_vec(), reed_solomon_simd::encode(4, 2, shards.as_slice()).unwrap()].concat()
-        }).await.unwrap();
+        let recovery_shards = actix_web::rt::task::spawn_blocking(move|| -> Result<Vec<Vec<u8>>, ShardError> {
+            let recovery = reed_solomon_simd::encode(4, 2, shards.as_slice())
+                .map_err(|err| ShardError::EncodingError(err.to_string()))?;
+            Ok([shards.to_vec(), recovery].concat())
+        }).await.map_err(|err| ShardError::EncodingError(err.to_string()))??;
 
 
         let mut futures = Vec::new();
 
 
-        for (i, shard) in recovery_shards.into_iter().enumerate() {
-
-            let node_id: u32 = if i as u32 >= current_node {
-                i+1
-            } else {
-                i
-            } as u32;
-
-            if let Some(channel) = channels.get(&node_id) {
-                let write_future = self.write_shard_to_node(
+        for ((node_id, channel), shard) in holders.into_iter().zip(recovery_shards) {
+            let write_future = match channel {
+                Ok(channel) if shard_batcher::is_running() => {
+                    shard_batcher::write_shard(node_id, channel, location_id.clone(), shard, span.context()).boxed()
+                }
+                Ok(channel) => self.write_shard_to_node(
                     addr.clone(),
-                    node_id.clone(),
-                    channel.clone(),
+                    node_id,
+                    channel,
                     &location_id,
-                    shard.to_vec(),
-                );
-                futures.push(write_future);
-            } else {
-                (println!("No channel found for node {}", node_id));
-            }
+                    shard,
+                    span.context(),
+                ).boxed(),
+                Err(reason) => {
+                    let err = ShardError::Unavailable(format!("Shard of {} not written to node {}: {}", location_id, node_id, reason));
+                    async move { Err(err) }.boxed()
+                }
+            };
+            futures.push(write_future);
         }
 
-        try_join_all(futures).boxed().await
+        // Every shard gets its chance before the write reports the first failure
+        let result = join_all(futures).await.into_iter().collect::<Result<Vec<()>, _>>();
+        if let Err(err) = &result {
+            span.set_error(err);
+        }
+        result
     }
 
     async fn write_shard_to_node(
@@ -79,47 +84,119 @@ impl LocationActor {
         channel: Channel,
         location_id: &String,
         shard_data: Vec<u8>,
+        parent: TraceContext,
     ) -> Result<(), ShardError> {
-        let mut client = rs::rs::rs_client::RsClient::new(channel);
-        
-        let request = Request::new(WriteShardRequest {
+        let client = rs::rs::rs_client::RsClient::new(channel);
+        let mut span = Span::start("rpc.write_shard", Some(&parent), SpanKind::Client);
+        span.set_attribute("peer.node_id", node_id);
+
+        // PutShard overwrites unconditionally, so a late retry could land on top of a newer
+        // version of the location; shard writes get a single attempt, which takes the payload
+        let mut shard = Some(WriteShardRequest {
             location_id: location_id.clone(),
             shard: shard_data,
         });
-        
+        let response = rpc::call(RpcKind::ShardWrite, &span.context(), false, |timeout| {
+            let mut client = client.clone();
+            let mut request = Request::new(shard.take().unwrap_or_default());
+            span.context().inject(&mut request);
+            sender::stamp(&mut request);
+            request.set_timeout(timeout);
+            async move { client.write_shard_request(request).await }
+        }).await;
+
         // Send the RPC call and handle errors
-        match client.write_shard_request(request).await {
+        match response {
             Ok(_) => Ok(()),
             Err(status) => {
-                if Self::is_connection_error(&status) {
-                    addr.do_send(ResetChannel(node_id));
-                }
+                record_rpc_failure(&addr, node_id, &status);
                 
+                span.set_error(&status);
                 Err(ShardError::RpcError(format!("Failed to write shard: {}", status)))
             }
         }
     }
     
-    fn is_connection_error(status: &Status) -> bool {
-        matches!(
-            status.code(),
-            tonic::Code::Unavailable | 
-            tonic::Code::DeadlineExceeded | 
-            tonic::Code::Cancelled |
-            tonic::Code::Aborted |
-            tonic::Code::Unknown
-        )
+    // Drops the peers' shards of a deleted location. Reads rebuild locations without an
+    // owner record from shards, so this fails unless too few are left to rebuild it.
+    async fn delete_shards(addr: Arc<Addr<ChannelManager>>, location_id: String, trace: TraceContext) -> Result<(), ShardError> {
+        let mut span = Span::start("location.delete", Some(&trace), SpanKind::Internal);
+        span.set_attribute("location_id", &location_id);
+
+        let holders = addr.send(GetShardHolders).await?;
+        let deletes = holders.into_iter().map(|(node_id, channel)| {
+            let addr = addr.clone();
+            let location_id = location_id.clone();
+            let parent = span.context();
+            async move {
+                let channel = match channel {
+                    Ok(channel) => channel,
+                    Err(reason) => {
+                        error!("Shard of {} not deleted on node {}: {}", location_id, node_id, reason);
+                        return false;
+                    }
+                };
+                let client = rs::rs::rs_client::RsClient::new(channel);
+                let mut span = Span::start("rpc.delete_shard", Some(&parent), SpanKind::Client);
+                span.set_attribute("peer.node_id", node_id);
+                // A retried delete could drop the shard of a write that followed it
+
+                let response = rpc::call(RpcKind::ShardWrite, &span.context(), false, |timeout| {
+                    let mut client = client.clone();
+                    let mut request = Request::new(DeleteShardRequest { location_id: location_id.clone() });
+                    span.context().inject(&mut request);
+                    sender::stamp(&mut request);
+                    request.set_timeout(timeout);
+                    async move { client.delete_shard(request).await }
+                }).await;
+
+                if let Err(status) = &response {
+                    record_rpc_failure(&addr, node_id, status);
+                    span.set_error(status);
+                    error!("Failed to delete shard of {} on node {}: {}", location_id, node_id, status);
+                }
+                response.is_ok()
+            }
+        });
+        let dropped = join_all(deletes).await.into_iter().filter(|dropped| *dropped).count();
+
+        if TOTAL_SHARDS - dropped >= REQUIRED_SHARDS {
+            let err = ShardError::Unavailable(format!("Dropped only {} of {} shards of {}", dropped, TOTAL_SHARDS, location_id));
+            span.set_error(&err);
+            return Err(err);
+        }
+        Ok(())
     }
 }
 
+// The last field is the modification_count the write expects to replace, if any
 #[derive(Message)]
-#[rtype(result = "Result<Vec<()>,ShardError>")]
-pub struct PutLocation(pub ExtendedLocationStats, pub Arc<Addr<ChannelManager>>);
+#[rtype(result = "Result<EnrichedLocationStats, ShardError>")]
+pub struct PutLocation(pub ExtendedLocationStats, pub Arc<Addr<ChannelManager>>, pub TraceContext, pub Option<IfMatch>);
+
+// Merges the set fields into the stored stats; fields as in PutLocation
+#[derive(Message)]
+#[rtype(result = "Result<EnrichedLocationStats, ShardError>")]
+pub struct PatchLocation(pub String, pub LocationPatch, pub Arc<Addr<ChannelManager>>, pub TraceContext, pub Option<IfMatch>);
 
 #[derive(Message)]
 #[rtype(result = "Result<EnrichedLocationStats, ()>")]
 pub struct GetLocation;
 
+// Removes the owner record and the peers' shards of a location
+#[derive(Message)]
+#[rtype(result = "Result<(), ShardError>")]
+pub struct DeleteLocation(pub String, pub Arc<Addr<ChannelManager>>, pub TraceContext);
+
+// Re-encodes the current record and writes all of its shards again, used by repairs
+#[derive(Message)]
+#[rtype(result = "Result<(), ShardError>")]
+pub struct RewriteShards(pub String, pub Arc<Addr<ChannelManager>>, pub TraceContext);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClearShard;
+
 #[derive(Message)]
 #[rtype(result = "Result<(), ShardError>")]
 pub struct PutShard(pub Vec<u8>);
@@ -135,23 +212,126 @@ impl Actor for LocationActor {
 
 
 impl Handler<PutLocation> for LocationActor {
-    type Result = AtomicResponse<Self, Result<Vec<()>, ShardError>>;
+    type Result = AtomicResponse<Self, Result<EnrichedLocationStats, ShardError>>;
      
     fn handle(&mut self, msg: PutLocation, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(expected) = &msg.3 {
+            if let Err(err) = expected.check(&msg.0.location_id, self.location.is_some(), self.modification_count) {
+                return AtomicResponse::new(Box::pin(ready(Err(err))));
+            }
+        }
+
         self.modification_count += 1;
         self.location = Some(Box::new(msg.0.to_basic()));
         let data = EnrichedLocationStats::from(self.modification_count, msg.0.to_basic());
         let actor = self.clone();
         let location_id = msg.0.location_id.clone();
+        // The reading is committed here, so it alerts and reaches watchers even if the
+        // shard fan-out fails
+        alerts::evaluate(&location_id, &data);
+        feed::publish(location_id.clone(), data.clone());
 
         return AtomicResponse::new(Box::pin(
             async move { 
-                actor.write(msg.1, location_id, data).await
+                actor.write(msg.1, location_id, data.clone(), msg.2).await?;
+                Ok::<_, ShardError>(data)
             }.into_actor(self)
         ));
     }
 }
 
+impl Handler<PatchLocation> for LocationActor {
+    type Result = AtomicResponse<Self, Result<EnrichedLocationStats, ShardError>>;
+
+    fn handle(&mut self, msg: PatchLocation, _ctx: &mut Self::Context) -> Self::Result {
+        let location_id = msg.0;
+        let Some(base) = &self.location else {
+            let err = ShardError::NotFoundError(format!("Location {} not found", location_id));
+            return AtomicResponse::new(Box::pin(ready(Err(err))));
+        };
+        if let Some(expected) = &msg.4 {
+            if let Err(err) = expected.check(&location_id, true, self.modification_count) {
+                return AtomicResponse::new(Box::pin(ready(Err(err))));
+            }
+        }
+
+        let merged = msg.1.apply(base);
+        self.modification_count += 1;
+        self.location = Some(Box::new(merged.clone()));
+        let data = EnrichedLocationStats::from(self.modification_count, merged);
+        let actor = self.clone();
+        alerts::evaluate(&location_id, &data);
+        feed::publish(location_id.clone(), data.clone());
+
+        AtomicResponse::new(Box::pin(
+            async move {
+                actor.write(msg.2, location_id, data.clone(), msg.3).await?;
+                Ok::<_, ShardError>(data)
+            }.into_actor(self)
+        ))
+    }
+}
+
+// modification_count is kept, and bumped by the delete, so a later write of the same
+// location doesn't reuse an ETag a client may still hold. The record comes back when
+// the shards can't be dropped, the location stays owned and a scrub can repair it.
+impl Handler<DeleteLocation> for LocationActor {
+    type Result = AtomicResponse<Self, Result<(), ShardError>>;
+
+    fn handle(&mut self, msg: DeleteLocation, _ctx: &mut Self::Context) -> Self::Result {
+        let location_id = msg.0;
+        let Some(location) = self.location.take() else {
+            let err = ShardError::NotFoundError(format!("Location {} not found", location_id));
+            return AtomicResponse::new(Box::pin(ready(Err(err))));
+        };
+
+        AtomicResponse::new(Box::pin(
+            Self::delete_shards(msg.1, location_id.clone(), msg.2)
+                .into_actor(self)
+                .map(move |result, act, _ctx| {
+                    match &result {
+                        Ok(()) => {
+                            act.modification_count += 1;
+                            feed::publish_delete(location_id, EnrichedLocationStats::from(act.modification_count, *location));
+                        }
+                        Err(_) => act.location = Some(location),
+                    }
+                    result
+                })
+        ))
+    }
+}
+
+// Atomic like the writes, so a repair never puts back shards older than a concurrent write's
+impl Handler<RewriteShards> for LocationActor {
+    type Result = AtomicResponse<Self, Result<(), ShardError>>;
+
+    fn handle(&mut self, msg: RewriteShards, _ctx: &mut Self::Context) -> Self::Result {
+        let location_id = msg.0;
+        let Some(location) = &self.location else {
+            let err = ShardError::NotFoundError(format!("Location {} not found", location_id));
+            return AtomicResponse::new(Box::pin(ready(Err(err))));
+        };
+        let data = EnrichedLocationStats::from(self.modification_count, (**location).clone());
+        let actor = self.clone();
+
+        AtomicResponse::new(Box::pin(
+            async move {
+                actor.write(msg.1, location_id, data, msg.2).await?;
+                Ok::<_, ShardError>(())
+            }.into_actor(self)
+        ))
+    }
+}
+
+impl Handler<ClearShard> for LocationActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ClearShard, _ctx: &mut Self::Context) -> Self::Result {
+        self.shard = None;
+    }
+}
+
 impl Handler<GetLocation> for LocationActor {
     type Result = Result<EnrichedLocationStats, ()>;
     
@@ -189,15 +369,24 @@ impl Handler<GetShard> for LocationActor {
 }
 
 
-use tonic::{Request, Status};
-use crate::conn_manager::{ChannelManager, GetAllChannels, ResetChannel};
-use crate::dto::{EnrichedLocationStats, ExtendedLocationStats, LocationStats, ShardError};
+use tonic::Request;
+use crate::conn_manager::{ChannelManager, GetShardHolders};
+use crate::constants::{REQUIRED_SHARDS, TOTAL_SHARDS};
+use crate::coordinator::record_rpc_failure;
+use crate::dto::{EnrichedLocationStats, ExtendedLocationStats, IfMatch, LocationPatch, LocationStats, ShardError};
+use crate::alerts;
+use crate::feed;
+use crate::rpc::{self, RpcKind};
+use crate::sender;
+use crate::shard_batcher;
 use crate::rs;
-use crate::rs::rs::WriteShardRequest;
+use crate::rs::rs::{DeleteShardRequest, WriteShardRequest};
+use crate::trace::{Span, SpanKind, TraceContext};
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::conn_manager::{GetAllChannels, ResetChannel};
     use actix::Actor;
     use std::time::Duration;
     use std::sync::Arc;
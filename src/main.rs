@@ -15,13 +15,31 @@ mod node;
 mod conn_manager;
 mod util;
 mod constants;
+mod trace;
+mod shutdown;
+mod validation;
+mod feed;
+mod query;
+mod aggregate;
+mod alerts;
+mod coordinator;
+mod shard_batcher;
+mod protocol;
+mod health;
+mod admin;
+mod tls;
+mod auth;
+mod sender;
+mod rpc;
+#[cfg(test)]
+mod test_support;
 
 
 
 
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    admin::init_logging();
     let current_node_ip_value = env::var("CURRENT_NODE_IP").unwrap();
     let current_node_ip = current_node_ip_value.trim();
     let all_node_ips: Vec<String> =  env::var("ALL_NODE_IPS").unwrap()
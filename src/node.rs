@@ -1,61 +1,475 @@
+use std::pin::Pin;
 use std::sync::Arc;
 
 use actix::Addr;
-use log::{error, info};
+use futures::stream::{self, Stream, StreamExt};
+use log::{error, info, warn};
 use tonic::{Request, Response, Status};
+use crate::aggregate::{self, AggregateSpec};
+use crate::alerts::{self, AlertEvent, AlertRule};
+use crate::auth::{self, Permission};
 use crate::conn_manager::ChannelManager;
-use crate::constants::ROOT_ACTOR_POOL_SIZE;
-use crate::dto::{ExtendedLocationStats, LocationStats};
-use crate::location_actor::{GetLocation, GetShard, PutLocation, PutShard};
-use crate::root_actor::{self, GetAddr, RootActor};
-use crate::rs::rs::{self, EnrichedLocationStats, GetShardRequest, GetShardResponse};
-use crate::rs::rs::{RouteWriteRequest, RouteWriteResponse, WriteShardRequest, WriteShardResponse};
-use crate::util::get_owner_node_id;
+use crate::coordinator::Coordinator;
+use crate::constants::{BATCH_READ_CONCURRENCY, BATCH_WRITE_CONCURRENCY, MAX_LIST_LIMIT};
+use crate::dto::{self, BatchPutItem, IfMatch, LocationPatch, LocationStats, ShardError};
+use crate::feed::{self, WatchFilter};
+use crate::health::node_status;
+use crate::protocol::{self, Compatibility, ProtocolInfo};
+use crate::query::{self, Predicate};
+use crate::location_actor::{ClearShard, GetLocation, GetShard, PutShard};
+use crate::root_actor::{delete_location, get_location, list_owned, lookup, patch_location, put_location, root_actor_for, FindAddr, RootActor};
+use crate::rs::rs::{self, AggregateRequest, AggregateResponse, AlertHistoryRequest, AlertHistoryResponse, DeleteAlertRuleRequest, DeleteAlertRuleResponse, EnrichedLocationStats, GetLocationsRequest, GetLocationsResponse, GetShardRequest, GetShardResponse, ListAlertRulesRequest, ListAlertRulesResponse, ListLocationsRequest, ListLocationsResponse, LocationResult, PingRequest, PingResponse, PutAlertRuleResponse, QueryLocationsRequest, QueryLocationsResponse, SubscribeRequest};
+use crate::rs::rs::{BatchGetLocationsRequest, BatchGetLocationsResponse, BatchPutLocationsRequest, BatchPutLocationsResponse, DeleteLocationRequest, DeleteLocationResponse, DeleteShardRequest, DeleteShardResponse, GetLocationRequest, GetLocationResponse, PutLocationRequest, PutLocationResponse, RouteDeleteRequest, RouteDeleteResponse};
+use crate::rs::rs::location_service_server::LocationService;
+use crate::rs::rs::{HandshakeRequest, HandshakeResponse, NodeStatusRequest, NodeStatusResponse};
+use crate::rs::rs::{RoutePatchRequest, RoutePatchResponse, RouteWriteBatchRequest, RouteWriteBatchResponse, RouteWriteRequest, RouteWriteResponse, WriteResult, WriteShardBatchRequest, WriteShardBatchResponse, WriteShardRequest, WriteShardResponse};
+use crate::rpc;
+use crate::sender;
+use crate::shutdown::{Drain, DrainGuard};
+use crate::trace::{Span, SpanKind, TraceContext};
+use crate::validation::{validate_location_id, validate_write, ValidationConfig};
 
 
 pub struct Node {
+    pub current_node: u32,
     pub root_actor: Vec<Addr<RootActor>>,
-    pub channel_manager: Arc<Addr<ChannelManager>>
+    pub channel_manager: Arc<Addr<ChannelManager>>,
+    pub drain: Drain,
+    pub validation: ValidationConfig,
+    pub endpoints: Vec<String>,
 }
 
 #[tonic::async_trait]
 impl rs::rs_server::Rs for Node {
+    #[allow(non_camel_case_types)]
+    type subscribeStream = Pin<Box<dyn Stream<Item = Result<rs::LocationChange, Status>> + Send>>;
+
     async fn route_write(&self, request: Request<RouteWriteRequest>) -> Result<Response<RouteWriteResponse>, Status> {
+        let Some(_in_flight) = self.drain.enter() else {
+            return Err(Status::unavailable("node is shutting down"));
+        };
+        let mut span = Span::start("rs.route_write", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
         let data = request.into_inner();
         let location_id = data.location_id.clone();
-        let root_actor = self.root_actor.get((get_owner_node_id(data.location_id.clone()) % ROOT_ACTOR_POOL_SIZE) as usize).unwrap();
-        let addr =  root_actor.send(GetAddr(data.location_id.clone())).await.unwrap().unwrap();
-
-        if let Err(err) = addr.send(PutLocation(ExtendedLocationStats::from_basic(data.location_id.clone(), LocationStats::from(data)), self.channel_manager.clone())).await {
-            error!("Failed to put location stats: {} {}", err, location_id);
-            return Err(Status::internal(err.to_string()));
+        let expected = IfMatch::from_proto(data.expected_modification_count, data.expected_modification_counts.clone(), data.expect_exists);
+        span.set_attribute("location_id", &location_id);
+        sender::authorize_route(self.current_node, &location_id, "routeWrite")?;
+        let stats = LocationStats::from(data);
+        validate_write(&location_id, &stats, &self.validation)?;
+        match put_location(&self.root_actor, self.channel_manager.clone(), location_id.clone(), stats, expected, span.context()).await {
+            Ok(committed) => Ok(Response::new(RouteWriteResponse { modification_count: committed.modification_count })),
+            Err(err) => {
+                span.set_error(&err);
+                error!("Failed to put location stats: {} {}", err, location_id);
+                Err(err.into())
+            }
         }
+    }
 
-   Ok(Response::new(RouteWriteResponse {}))
-}
+    async fn route_patch(&self, request: Request<RoutePatchRequest>) -> Result<Response<RoutePatchResponse>, Status> {
+        let Some(_in_flight) = self.drain.enter() else {
+            return Err(Status::unavailable("node is shutting down"));
+        };
+        let mut span = Span::start("rs.route_patch", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let data = request.into_inner();
+        let location_id = data.location_id.clone();
+        span.set_attribute("location_id", &location_id);
+        sender::authorize_route(self.current_node, &location_id, "routePatch")?;
+        let patch = LocationPatch::from_proto(&data);
+        validate_write(&location_id, &patch, &self.validation)?;
+        match patch_location(&self.root_actor, self.channel_manager.clone(), location_id.clone(), patch, IfMatch::from_proto(data.expected_modification_count, data.expected_modification_counts, data.expect_exists), span.context()).await {
+            Ok(committed) => Ok(Response::new(RoutePatchResponse { location_stats: Some(committed.to_proto()) })),
+            Err(err) => {
+                span.set_error(&err);
+                error!("Failed to patch location stats: {} {}", err, location_id);
+                Err(err.into())
+            }
+        }
+    }
 
     async fn write_shard_request(&self, request: Request<WriteShardRequest>) -> Result<Response<WriteShardResponse>, Status> {
+        let mut span = Span::start("rs.write_shard", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let from = sender::sender(&request, "writeShardRequest")?;
         let data = request.into_inner();
         info!("Write shard request: {}", data.location_id);
-        let root_actor = self.root_actor.get((get_owner_node_id(data.location_id.clone()) % ROOT_ACTOR_POOL_SIZE) as usize).unwrap();
-        let addr =  root_actor.send(GetAddr(data.location_id)).await.unwrap().unwrap();
-        addr.send(PutShard(data.shard)).await.unwrap()
-            .map(|_| Response::new(WriteShardResponse {}))
-            .map_err(|err| Status::internal(err.to_string()))
+        span.set_attribute("location_id", &data.location_id);
+        sender::authorize_shard(from, self.current_node, &data.location_id, "writeShardRequest")?;
+        let addr = lookup(&self.root_actor, &data.location_id).await?;
+        addr.send(PutShard(data.shard)).await.map_err(ShardError::from)??;
+        Ok(Response::new(WriteShardResponse {}))
+    }
+
+    async fn write_shard_batch(&self, request: Request<WriteShardBatchRequest>) -> Result<Response<WriteShardBatchResponse>, Status> {
+        let mut span = Span::start("rs.write_shard_batch", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let from = sender::sender(&request, "writeShardBatch")?;
+        let shards = request.into_inner().shards;
+        span.set_attribute("batch.size", shards.len());
+
+        let results: Vec<WriteResult> = stream::iter(shards.into_iter().map(|data| async move {
+            let result = match sender::authorize_shard(from, self.current_node, &data.location_id, "writeShardBatch") {
+                Ok(()) => match lookup(&self.root_actor, &data.location_id).await {
+                    Ok(addr) => addr.send(PutShard(data.shard)).await.map_err(ShardError::from).and_then(|res| res),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+            if let Err(err) = &result {
+                error!("Failed to store shard of {}: {}", data.location_id, err);
+            }
+            write_result(data.location_id, result)
+        })).buffered(BATCH_WRITE_CONCURRENCY).collect().await;
+
+        Ok(Response::new(WriteShardBatchResponse { results }))
     }
 
     async fn get_shard_request(&self, request: Request<GetShardRequest>) -> Result<Response<GetShardResponse>, Status> {
+        let mut span = Span::start("rs.get_shard", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
         let data = request.into_inner();
-        let root_actor = self.root_actor.get((get_owner_node_id(data.location_id.clone()) % ROOT_ACTOR_POOL_SIZE) as usize).unwrap();
-        let addr =  root_actor.send(GetAddr(data.location_id.clone())).await.unwrap().unwrap();
-        let shard = addr.send(GetShard(data.location_id)).await.unwrap().ok();
-        let location_stats = addr.send(GetLocation{}).await.unwrap().ok();
+        span.set_attribute("location_id", &data.location_id);
+        let addr = lookup(&self.root_actor, &data.location_id).await?;
+        let shard = addr.send(GetShard(data.location_id)).await.map_err(ShardError::from)?.ok();
+        let location_stats = addr.send(GetLocation{}).await.map_err(ShardError::from)?.ok();
 
         if shard.is_none() && location_stats.is_none() {
+            span.set_error("shard and location are none");
             return Err(Status::not_found("shard and location are none"));
         }
 
         return Ok(Response::new(GetShardResponse { shard: shard, location_stats: location_stats.map(|l| EnrichedLocationStats {
             id:l.id,seismic_activity:l.seismic_activity, temperature_c: l.temperature_c, radiation_level: l.radiation_level, modification_count: l.modification_count }) }))
     }
+
+    async fn ping(&self, _request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        Ok(Response::new(PingResponse { node_id: self.current_node }))
+    }
+
+    // Peers call this when probing us; each side judges compatibility on its own
+    async fn handshake(&self, request: Request<HandshakeRequest>) -> Result<Response<HandshakeResponse>, Status> {
+        let data = request.into_inner();
+        let local = ProtocolInfo::local();
+        if let Some(info) = &data.info {
+            protocol::record_peer_version(data.node_id, info.protocol_version);
+            if let Compatibility::Incompatible(reason) = local.compatibility(&ProtocolInfo::from_proto(info)) {
+                warn!("Handshake from incompatible node {}: {}", data.node_id, reason);
+            }
+        }
+        Ok(Response::new(HandshakeResponse { node_id: self.current_node, info: Some(local.to_proto()) }))
+    }
+
+    async fn route_write_batch(&self, request: Request<RouteWriteBatchRequest>) -> Result<Response<RouteWriteBatchResponse>, Status> {
+        let Some(_in_flight) = self.drain.enter() else {
+            return Err(Status::unavailable("node is shutting down"));
+        };
+        let mut span = Span::start("rs.route_write_batch", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let writes = request.into_inner().writes;
+        span.set_attribute("batch.size", writes.len());
+
+        let results: Vec<WriteResult> = stream::iter(writes.into_iter().map(|data| {
+            let location_id = data.location_id.clone();
+            let expected = IfMatch::from_proto(data.expected_modification_count, data.expected_modification_counts.clone(), data.expect_exists);
+            let stats = LocationStats::from(data);
+            let trace = span.context();
+            async move {
+                let checked = sender::authorize_route(self.current_node, &location_id, "routeWriteBatch")
+                    .and_then(|()| validate_write(&location_id, &stats, &self.validation));
+                let result = match checked {
+                    Ok(()) => put_location(&self.root_actor, self.channel_manager.clone(), location_id.clone(), stats, expected, trace).await.map(|_| ()),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = &result {
+                    error!("Failed to put location stats: {} {}", err, location_id);
+                }
+                write_result(location_id, result)
+            }
+        })).buffered(BATCH_WRITE_CONCURRENCY).collect().await;
+
+        Ok(Response::new(RouteWriteBatchResponse { results }))
+    }
+
+    async fn get_locations(&self, request: Request<GetLocationsRequest>) -> Result<Response<GetLocationsResponse>, Status> {
+        let mut span = Span::start("rs.get_locations", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let location_ids = request.into_inner().location_ids;
+        span.set_attribute("batch.size", location_ids.len());
+
+        let results: Vec<LocationResult> = stream::iter(location_ids.into_iter().map(|location_id| async move {
+            let result = get_location(&self.root_actor, &location_id).await;
+            location_result(location_id, result)
+        })).buffered(BATCH_READ_CONCURRENCY).collect().await;
+
+        Ok(Response::new(GetLocationsResponse { results }))
+    }
+
+    // The document GET /cluster serves, for a peer answering /cluster/all
+    async fn node_status(&self, _request: Request<NodeStatusRequest>) -> Result<Response<NodeStatusResponse>, Status> {
+        let status = node_status(self.current_node, &self.root_actor, &self.channel_manager, &self.endpoints).await.map_err(ShardError::from)?;
+        let status = serde_json::to_vec(&status).map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(NodeStatusResponse { status }))
+    }
+
+    // Streams changes committed on this node, peers subscribe here on behalf of their watchers
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::subscribeStream>, Status> {
+        let filter = WatchFilter::from_proto(request.into_inner());
+        if filter.is_empty() {
+            return Err(Status::invalid_argument("subscription needs location ids or prefixes"));
+        }
+        let subscription = feed::subscribe(filter).await?;
+        Ok(Response::new(Box::pin(feed::changes(subscription).map(|change| Ok(change.to_proto())))))
+    }
+
+    async fn query_locations(&self, request: Request<QueryLocationsRequest>) -> Result<Response<QueryLocationsResponse>, Status> {
+        let mut span = Span::start("rs.query_locations", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let data = request.into_inner();
+        let predicates = data.predicates.iter().map(Predicate::from_proto).collect::<Result<Vec<_>, _>>()?;
+        let after = (!data.after.is_empty()).then_some(data.after);
+        let limit = (data.limit as usize).clamp(1, MAX_LIST_LIMIT);
+        let matches = query::scan(&self.root_actor, &predicates, after, limit).await?;
+        span.set_attribute("query.matches", matches.len());
+        Ok(Response::new(QueryLocationsResponse { matches: matches.iter().map(|m| m.to_proto()).collect() }))
+    }
+
+    async fn aggregate(&self, request: Request<AggregateRequest>) -> Result<Response<AggregateResponse>, Status> {
+        let mut span = Span::start("rs.aggregate", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let spec = AggregateSpec::from_proto(request.get_ref());
+        let partials = aggregate::aggregate(&self.root_actor, &spec).await?;
+        span.set_attribute("aggregate.groups", partials.len());
+        Ok(Response::new(AggregateResponse {
+            groups: partials.iter().map(|(key, partial)| partial.to_proto(key)).collect(),
+        }))
+    }
+
+    // Alert rules are replicated to every node by whichever node received the change
+    async fn put_alert_rule(&self, request: Request<rs::AlertRule>) -> Result<Response<PutAlertRuleResponse>, Status> {
+        alerts::put_rule(AlertRule::from_proto(request.into_inner()))?;
+        Ok(Response::new(PutAlertRuleResponse {}))
+    }
+
+    async fn delete_alert_rule(&self, request: Request<DeleteAlertRuleRequest>) -> Result<Response<DeleteAlertRuleResponse>, Status> {
+        let deleted = alerts::delete_rule(&request.into_inner().id)?;
+        Ok(Response::new(DeleteAlertRuleResponse { deleted }))
+    }
+
+    async fn list_alert_rules(&self, _request: Request<ListAlertRulesRequest>) -> Result<Response<ListAlertRulesResponse>, Status> {
+        let rules = alerts::rules()?.iter().map(AlertRule::to_proto).collect();
+        Ok(Response::new(ListAlertRulesResponse { rules, deleted_ids: alerts::deleted_rule_ids()? }))
+    }
+
+    // This node's alerts, for a peer answering GET /alerts/history
+    async fn alert_history(&self, request: Request<AlertHistoryRequest>) -> Result<Response<AlertHistoryResponse>, Status> {
+        let limit = (request.into_inner().limit as usize).min(MAX_LIST_LIMIT);
+        let events = alerts::history(limit).await?.iter().map(AlertEvent::to_proto).collect();
+        Ok(Response::new(AlertHistoryResponse { events }))
+    }
+
+    async fn route_delete(&self, request: Request<RouteDeleteRequest>) -> Result<Response<RouteDeleteResponse>, Status> {
+        let Some(_in_flight) = self.drain.enter() else {
+            return Err(Status::unavailable("node is shutting down"));
+        };
+        let mut span = Span::start("rs.route_delete", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let location_id = request.into_inner().location_id;
+        span.set_attribute("location_id", &location_id);
+        sender::authorize_route(self.current_node, &location_id, "routeDelete")?;
+        match delete_location(&self.root_actor, self.channel_manager.clone(), location_id.clone(), span.context()).await {
+            Ok(()) => Ok(Response::new(RouteDeleteResponse {})),
+            Err(err) => {
+                span.set_error(&err);
+                error!("Failed to delete location: {} {}", err, location_id);
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn delete_shard(&self, request: Request<DeleteShardRequest>) -> Result<Response<DeleteShardResponse>, Status> {
+        let mut span = Span::start("rs.delete_shard", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let from = sender::sender(&request, "deleteShard")?;
+        let location_id = request.into_inner().location_id;
+        span.set_attribute("location_id", &location_id);
+        sender::authorize_shard(from, self.current_node, &location_id, "deleteShard")?;
+        let addr = root_actor_for(&self.root_actor, &location_id)?
+            .send(FindAddr(location_id))
+            .await
+            .map_err(ShardError::from)?;
+        if let Some(addr) = addr {
+            addr.send(ClearShard).await.map_err(ShardError::from)?;
+        }
+        Ok(Response::new(DeleteShardResponse {}))
+    }
+
+    async fn list_locations(&self, request: Request<ListLocationsRequest>) -> Result<Response<ListLocationsResponse>, Status> {
+        let data = request.into_inner();
+        let after = (!data.after.is_empty()).then_some(data.after);
+        let limit = (data.limit as usize).clamp(1, MAX_LIST_LIMIT);
+        let location_ids = list_owned(&self.root_actor, after, limit).await?;
+        Ok(Response::new(ListLocationsResponse { location_ids }))
+    }
+}
+
+// Client-facing gRPC API, the counterpart of the HTTP handlers in api.rs
+pub struct LocationApi {
+    pub coordinator: Coordinator,
+    pub drain: Drain,
+}
+
+impl LocationApi {
+    fn enter(&self) -> Result<DrainGuard, Status> {
+        self.drain.enter().ok_or_else(|| Status::unavailable("node is shutting down"))
+    }
+}
+
+#[tonic::async_trait]
+impl LocationService for LocationApi {
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<rs::LocationChange, Status>> + Send>>;
+
+    async fn put_location(&self, request: Request<PutLocationRequest>) -> Result<Response<PutLocationResponse>, Status> {
+        auth::require_grpc(&request, Permission::Write, "PutLocation")?;
+        let _in_flight = self.enter()?;
+        let mut span = Span::start("grpc.put_location", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        span.set_deadline(rpc::grpc_deadline(&request));
+        let data = request.into_inner();
+        span.set_attribute("location_id", &data.location_id);
+        let stats = data.stats.map(LocationStats::from_proto)
+            .ok_or_else(|| Status::invalid_argument("stats are required"))?;
+        match self.coordinator.put(data.location_id.clone(), stats, IfMatch::from_count(data.expected_modification_count), span.context()).await {
+            Ok(committed) => Ok(Response::new(PutLocationResponse { location_stats: Some(committed.to_proto()) })),
+            Err(err) => {
+                span.set_error(&err);
+                error!("Failed to put location stats: {} {}", err, data.location_id);
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn get_location(&self, request: Request<GetLocationRequest>) -> Result<Response<GetLocationResponse>, Status> {
+        auth::require_grpc(&request, Permission::Read, "GetLocation")?;
+        let mut span = Span::start("grpc.get_location", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        span.set_deadline(rpc::grpc_deadline(&request));
+        let location_id = request.into_inner().location_id;
+        span.set_attribute("location_id", &location_id);
+        match self.coordinator.get(location_id, span.context()).await {
+            Ok(stats) => Ok(Response::new(GetLocationResponse { location_stats: Some(stats.to_proto()) })),
+            Err(err) => {
+                span.set_error(&err);
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn delete_location(&self, request: Request<DeleteLocationRequest>) -> Result<Response<DeleteLocationResponse>, Status> {
+        auth::require_grpc(&request, Permission::Write, "DeleteLocation")?;
+        let _in_flight = self.enter()?;
+        let mut span = Span::start("grpc.delete_location", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        span.set_deadline(rpc::grpc_deadline(&request));
+        let location_id = request.into_inner().location_id;
+        span.set_attribute("location_id", &location_id);
+        match self.coordinator.delete(location_id.clone(), span.context()).await {
+            Ok(()) => Ok(Response::new(DeleteLocationResponse {})),
+            Err(err) => {
+                span.set_error(&err);
+                error!("Failed to delete location: {} {}", err, location_id);
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn batch_put_locations(&self, request: Request<BatchPutLocationsRequest>) -> Result<Response<BatchPutLocationsResponse>, Status> {
+        auth::require_grpc(&request, Permission::Write, "BatchPutLocations")?;
+        let _in_flight = self.enter()?;
+        let mut span = Span::start("grpc.batch_put_locations", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        span.set_deadline(rpc::grpc_deadline(&request));
+        let items = request.into_inner().items;
+        span.set_attribute("batch.size", items.len());
+
+        // Items without stats can't be grouped with the rest, they fail on their own
+        let mut missing = Vec::new();
+        let mut batch = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            match item.stats {
+                Some(stats) => batch.push(BatchPutItem {
+                    location_id: item.location_id,
+                    stats: LocationStats::from_proto(stats),
+                    expected_modification_count: item.expected_modification_count,
+                }),
+                None => missing.push((i, item.location_id)),
+            }
+        }
+
+        let mut results: Vec<WriteResult> = self.coordinator.put_batch(batch, span.context()).await?
+            .into_iter()
+            .map(|(location_id, result)| write_result(location_id, result))
+            .collect();
+        for (i, location_id) in missing {
+            results.insert(i, write_result(location_id, Err(ShardError::InvalidInput("stats are required".to_owned()))));
+        }
+
+        Ok(Response::new(BatchPutLocationsResponse { results }))
+    }
+
+    async fn watch(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        auth::require_grpc(&request, Permission::Read, "Watch")?;
+        let mut span = Span::start("grpc.watch", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        let filter = WatchFilter::from_proto(request.into_inner());
+        if filter.is_empty() {
+            return Err(Status::invalid_argument("watch needs location ids or prefixes"));
+        }
+        for location_id in &filter.location_ids {
+            validate_location_id(location_id, &self.coordinator.validation)?;
+        }
+        span.set_attribute("watch.locations", filter.location_ids.len());
+
+        let changes = self.coordinator.watch(filter, span.context()).await.map_err(|err| {
+            span.set_error(&err);
+            Status::from(err)
+        })?;
+        Ok(Response::new(Box::pin(changes.map(|change| change.map(|change| change.to_proto()).map_err(Status::from)))))
+    }
+
+    async fn batch_get_locations(&self, request: Request<BatchGetLocationsRequest>) -> Result<Response<BatchGetLocationsResponse>, Status> {
+        auth::require_grpc(&request, Permission::Read, "BatchGetLocations")?;
+        let mut span = Span::start("grpc.batch_get_locations", TraceContext::from_metadata(&request).as_ref(), SpanKind::Server);
+        span.set_deadline(rpc::grpc_deadline(&request));
+        let location_ids = request.into_inner().location_ids;
+        span.set_attribute("batch.size", location_ids.len());
+
+        let results = self.coordinator.get_batch(location_ids, span.context()).await?
+            .into_iter()
+            .map(|(location_id, result)| location_result(location_id, result))
+            .collect();
+
+        Ok(Response::new(BatchGetLocationsResponse { results }))
+    }
+}
+
+pub fn write_result(location_id: String, result: Result<(), ShardError>) -> WriteResult {
+    match result {
+        Ok(()) => WriteResult { location_id, code: tonic::Code::Ok as i32, message: String::new() },
+        Err(err) => {
+            let status = Status::from(err);
+            WriteResult { location_id, code: status.code() as i32, message: status.message().to_owned() }
+        }
+    }
+}
+
+pub fn from_write_result(result: &WriteResult) -> Result<(), ShardError> {
+    if result.code == tonic::Code::Ok as i32 {
+        return Ok(());
+    }
+    Err(ShardError::from(Status::new(tonic::Code::from_i32(result.code), result.message.clone())))
+}
+
+pub fn location_result(location_id: String, result: Result<dto::EnrichedLocationStats, ShardError>) -> LocationResult {
+    match result {
+        Ok(stats) => LocationResult { location_id, code: tonic::Code::Ok as i32, message: String::new(), location_stats: Some(stats.to_proto()) },
+        Err(err) => {
+            let status = Status::from(err);
+            LocationResult { location_id, code: status.code() as i32, message: status.message().to_owned(), location_stats: None }
+        }
+    }
+}
+
+pub fn from_location_result(result: LocationResult) -> Result<dto::EnrichedLocationStats, ShardError> {
+    if result.code != tonic::Code::Ok as i32 {
+        return Err(ShardError::from(Status::new(tonic::Code::from_i32(result.code), result.message)));
+    }
+    result.location_stats
+        .map(dto::EnrichedLocationStats::from_proto)
+        .ok_or_else(|| ShardError::RpcError(format!("No stats for {} in response", result.location_id)))
 }
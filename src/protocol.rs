@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::rs::rs;
+
+// Bumped on any change to the node-to-node RPCs. Peers talk as long as each one's
+// version is at least the other's minimum.
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+// First version with writeShardBatch
+pub const SHARD_BATCH_VERSION: u32 = 2;
+// First version whose shard writes and deletes name their sender in x-rs-node-id
+pub const SENDER_HEADER_VERSION: u32 = 3;
+// Bumped on any change to how EnrichedLocationStats is split into shards
+pub const SHARD_FORMAT_VERSION: u32 = 1;
+pub const DATA_SHARDS: u32 = 4;
+pub const PARITY_SHARDS: u32 = 2;
+
+// Protocol each peer reported in its last handshake
+static PEER_VERSIONS: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
+
+// What a node advertises in the handshake
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ProtocolInfo {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub shard_format_version: u32,
+    pub data_shards: u32,
+    pub parity_shards: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "state", content = "reason", rename_all = "snake_case")]
+pub enum Compatibility {
+    // No handshake has completed yet, nothing is sent to the peer until one does
+    #[default]
+    Unknown,
+    Compatible,
+    // Usable, but the peer runs another protocol version
+    Degraded(String),
+    // Shards or RPCs can't be exchanged with the peer, it is left out of fan-outs
+    Incompatible(String),
+}
+
+impl Compatibility {
+    pub fn is_usable(&self) -> bool {
+        matches!(self, Compatibility::Compatible | Compatibility::Degraded(_))
+    }
+}
+
+pub fn record_peer_version(node_id: u32, protocol_version: u32) {
+    let previous = PEER_VERSIONS.lock().unwrap_or_else(PoisonError::into_inner).insert(node_id, protocol_version);
+    if previous != Some(protocol_version) {
+        info!("Node {} runs protocol {}", node_id, protocol_version);
+    }
+}
+
+pub fn peer_version(node_id: u32) -> Option<u32> {
+    PEER_VERSIONS.lock().unwrap_or_else(PoisonError::into_inner).get(&node_id).copied()
+}
+
+// Whether any peer last reported a protocol older than `version`
+pub fn any_peer_below(version: u32) -> bool {
+    PEER_VERSIONS.lock().unwrap_or_else(PoisonError::into_inner).values().any(|peer| *peer < version)
+}
+
+impl ProtocolInfo {
+    pub fn local() -> Self {
+        ProtocolInfo {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            shard_format_version: SHARD_FORMAT_VERSION,
+            data_shards: DATA_SHARDS,
+            parity_shards: PARITY_SHARDS,
+        }
+    }
+
+    // Nodes from before the handshake existed: protocol 1 with the original shard layout
+    pub fn legacy() -> Self {
+        ProtocolInfo {
+            protocol_version: 1,
+            min_protocol_version: 1,
+            shard_format_version: 1,
+            data_shards: 4,
+            parity_shards: 2,
+        }
+    }
+
+    pub fn to_proto(&self) -> rs::ProtocolInfo {
+        rs::ProtocolInfo {
+            protocol_version: self.protocol_version,
+            min_protocol_version: self.min_protocol_version,
+            shard_format_version: self.shard_format_version,
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+        }
+    }
+
+    pub fn from_proto(info: &rs::ProtocolInfo) -> Self {
+        ProtocolInfo {
+            protocol_version: info.protocol_version,
+            min_protocol_version: info.min_protocol_version,
+            shard_format_version: info.shard_format_version,
+            data_shards: info.data_shards,
+            parity_shards: info.parity_shards,
+        }
+    }
+
+    // Shards only decode when both sides split and encode them the same way;
+    // RPC versions only have to overlap
+    pub fn compatibility(&self, peer: &ProtocolInfo) -> Compatibility {
+        if peer.shard_format_version != self.shard_format_version {
+            return Compatibility::Incompatible(format!(
+                "shard format {} differs from ours ({})", peer.shard_format_version, self.shard_format_version
+            ));
+        }
+        if (peer.data_shards, peer.parity_shards) != (self.data_shards, self.parity_shards) {
+            return Compatibility::Incompatible(format!(
+                "erasure coding {}+{} differs from ours ({}+{})", peer.data_shards, peer.parity_shards, self.data_shards, self.parity_shards
+            ));
+        }
+        if peer.protocol_version < self.min_protocol_version || self.protocol_version < peer.min_protocol_version {
+            return Compatibility::Incompatible(format!(
+                "protocol {} (min {}) does not overlap ours {} (min {})",
+                peer.protocol_version, peer.min_protocol_version, self.protocol_version, self.min_protocol_version
+            ));
+        }
+        if peer.protocol_version != self.protocol_version {
+            return Compatibility::Degraded(format!("protocol {} while we run {}", peer.protocol_version, self.protocol_version));
+        }
+        Compatibility::Compatible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatibility() {
+        let local = ProtocolInfo::local();
+        assert_eq!(local.compatibility(&local), Compatibility::Compatible);
+
+        let older = ProtocolInfo { protocol_version: 1, min_protocol_version: 1, ..ProtocolInfo::local() };
+        assert!(matches!(local.compatibility(&older), Compatibility::Degraded(_)));
+
+        let newer = ProtocolInfo { protocol_version: PROTOCOL_VERSION + 2, min_protocol_version: PROTOCOL_VERSION + 1, ..ProtocolInfo::local() };
+        assert!(matches!(local.compatibility(&newer), Compatibility::Incompatible(_)));
+
+        let reformatted = ProtocolInfo { shard_format_version: SHARD_FORMAT_VERSION + 1, ..ProtocolInfo::local() };
+        assert!(!local.compatibility(&reformatted).is_usable());
+
+        let wider = ProtocolInfo { data_shards: 6, parity_shards: 3, ..ProtocolInfo::local() };
+        assert!(!local.compatibility(&wider).is_usable());
+
+        assert!(!Compatibility::Unknown.is_usable());
+    }
+
+    #[test]
+    fn test_peer_versions() {
+        assert_eq!(peer_version(5), None);
+        record_peer_version(5, SENDER_HEADER_VERSION - 1);
+        assert_eq!(peer_version(5), Some(SENDER_HEADER_VERSION - 1));
+        assert!(any_peer_below(SENDER_HEADER_VERSION));
+
+        record_peer_version(5, SENDER_HEADER_VERSION);
+        assert!(!any_peer_below(SENDER_HEADER_VERSION));
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use actix::Addr;
+use futures::future::try_join_all;
+use futures::stream::{self, StreamExt};
+
+use crate::constants::BATCH_READ_CONCURRENCY;
+use crate::dto::{EnrichedLocationStats, QueryMatch, ShardError};
+use crate::location_actor::GetLocation;
+use crate::root_actor::{ListOwnedAddrs, RootActor};
+use crate::rs::rs;
+
+// Owned locations read per round trip to a RootActor while scanning
+const SCAN_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatField {
+    SeismicActivity,
+    TemperatureC,
+    RadiationLevel,
+}
+
+impl StatField {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "seismic_activity" => Some(StatField::SeismicActivity),
+            "temperature_c" => Some(StatField::TemperatureC),
+            "radiation_level" => Some(StatField::RadiationLevel),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatField::SeismicActivity => "seismic_activity",
+            StatField::TemperatureC => "temperature_c",
+            StatField::RadiationLevel => "radiation_level",
+        }
+    }
+
+    pub fn value(&self, stats: &EnrichedLocationStats) -> f64 {
+        match self {
+            StatField::SeismicActivity => stats.seismic_activity,
+            StatField::TemperatureC => stats.temperature_c,
+            StatField::RadiationLevel => stats.radiation_level,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparator {
+    Gt(f64),
+    Gte(f64),
+    Lt(f64),
+    Lte(f64),
+    Eq(f64),
+    // Inclusive on both ends
+    Between(f64, f64),
+}
+
+impl Comparator {
+    // Parses `op:value`, or `between:min,max`
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let (op, value) = expr.split_once(':').ok_or_else(|| format!("expected op:value, got {}", expr))?;
+        let number = |s: &str| -> Result<f64, String> {
+            s.trim().parse::<f64>()
+                .ok()
+                .filter(|v| !v.is_nan())
+                .ok_or_else(|| format!("{} is not a number", s))
+        };
+
+        match op.trim() {
+            "gt" => Ok(Comparator::Gt(number(value)?)),
+            "gte" => Ok(Comparator::Gte(number(value)?)),
+            "lt" => Ok(Comparator::Lt(number(value)?)),
+            "lte" => Ok(Comparator::Lte(number(value)?)),
+            "eq" => Ok(Comparator::Eq(number(value)?)),
+            "between" => {
+                let (min, max) = value.split_once(',').ok_or_else(|| "between expects min,max".to_owned())?;
+                let (min, max) = (number(min)?, number(max)?);
+                if min > max {
+                    return Err(format!("between bounds are reversed: {} > {}", min, max));
+                }
+                Ok(Comparator::Between(min, max))
+            }
+            other => Err(format!("unknown operator {}", other)),
+        }
+    }
+
+    pub fn matches(&self, value: f64) -> bool {
+        match *self {
+            Comparator::Gt(x) => value > x,
+            Comparator::Gte(x) => value >= x,
+            Comparator::Lt(x) => value < x,
+            Comparator::Lte(x) => value <= x,
+            Comparator::Eq(x) => value == x,
+            Comparator::Between(min, max) => value >= min && value <= max,
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Comparator::Gt(x) => write!(f, "gt:{}", x),
+            Comparator::Gte(x) => write!(f, "gte:{}", x),
+            Comparator::Lt(x) => write!(f, "lt:{}", x),
+            Comparator::Lte(x) => write!(f, "lte:{}", x),
+            Comparator::Eq(x) => write!(f, "eq:{}", x),
+            Comparator::Between(min, max) => write!(f, "between:{},{}", min, max),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Predicate {
+    pub field: StatField,
+    pub comparator: Comparator,
+}
+
+impl Predicate {
+    pub fn parse(field: &str, expr: &str) -> Result<Self, ShardError> {
+        let stat = StatField::parse(field)
+            .ok_or_else(|| ShardError::InvalidInput(format!("Unknown query field {}", field)))?;
+        let comparator = Comparator::parse(expr)
+            .map_err(|err| ShardError::InvalidInput(format!("{}: {}", field, err)))?;
+        Ok(Predicate { field: stat, comparator })
+    }
+
+    pub fn matches(&self, stats: &EnrichedLocationStats) -> bool {
+        self.comparator.matches(self.field.value(stats))
+    }
+
+    pub fn to_proto(&self) -> rs::Predicate {
+        rs::Predicate {
+            field: self.field.name().to_owned(),
+            expr: self.comparator.to_string(),
+        }
+    }
+
+    pub fn from_proto(predicate: &rs::Predicate) -> Result<Self, ShardError> {
+        Predicate::parse(&predicate.field, &predicate.expr)
+    }
+}
+
+// All predicates have to hold; no predicates matches everything
+pub fn matches_all(predicates: &[Predicate], stats: &EnrichedLocationStats) -> bool {
+    predicates.iter().all(|predicate| predicate.matches(stats))
+}
+
+// One page of owner records after `after` in id order, with the last id scanned and
+// whether that was the final page. None once the RootActor has nothing left.
+pub async fn read_owned_page(root_actor: &Addr<RootActor>, after: Option<String>) -> Result<Option<(String, Vec<QueryMatch>, bool)>, ShardError> {
+    let page = root_actor.send(ListOwnedAddrs { after, limit: SCAN_PAGE_SIZE }).await?;
+    let Some((last, _)) = page.last() else {
+        return Ok(None);
+    };
+    let last = last.clone();
+    let exhausted = page.len() < SCAN_PAGE_SIZE;
+
+    let records: Vec<Option<QueryMatch>> = stream::iter(page.into_iter().map(|(location_id, addr)| async move {
+        let stats = addr.send(GetLocation).await.ok().and_then(|res| res.ok());
+        stats.map(|stats| QueryMatch { location_id, stats })
+    })).buffered(BATCH_READ_CONCURRENCY).collect().await;
+
+    Ok(Some((last, records.into_iter().flatten().collect(), exhausted)))
+}
+
+// Scans the owner records of one RootActor in id order until `limit` of them match
+async fn scan_root_actor(root_actor: &Addr<RootActor>, predicates: &[Predicate], after: Option<String>, limit: usize) -> Result<Vec<QueryMatch>, ShardError> {
+    let mut matches = Vec::new();
+    let mut cursor = after;
+
+    while matches.len() < limit {
+        let Some((last, records, exhausted)) = read_owned_page(root_actor, cursor).await? else {
+            break;
+        };
+        cursor = Some(last);
+        matches.extend(records.into_iter().filter(|m| matches_all(predicates, &m.stats)));
+
+        if exhausted {
+            break;
+        }
+    }
+
+    matches.truncate(limit);
+    Ok(matches)
+}
+
+// The first `limit` matching locations this node owns, sorted by id, after `after`
+pub async fn scan(pool: &[Addr<RootActor>], predicates: &[Predicate], after: Option<String>, limit: usize) -> Result<Vec<QueryMatch>, ShardError> {
+    let pages = try_join_all(pool.iter().map(|root_actor| scan_root_actor(root_actor, predicates, after.clone(), limit))).await?;
+    let mut matches: Vec<QueryMatch> = pages.into_iter().flatten().collect();
+    matches.sort_by(|a, b| a.location_id.cmp(&b.location_id));
+    matches.truncate(limit);
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::stats;
+
+    #[test]
+    fn test_parse_comparators() {
+        assert_eq!(Comparator::parse("gt:5").unwrap(), Comparator::Gt(5.0));
+        assert_eq!(Comparator::parse("between:1,2.5").unwrap(), Comparator::Between(1.0, 2.5));
+        assert!(Comparator::parse("between:3,1").is_err());
+        assert!(Comparator::parse("gt:abc").is_err());
+        assert!(Comparator::parse("gt:NaN").is_err());
+        assert!(Comparator::parse("approx:1").is_err());
+        assert!(Comparator::parse("5").is_err());
+    }
+
+    #[test]
+    fn test_predicates_are_anded() {
+        let predicates = vec![
+            Predicate::parse("radiation_level", "gt:0.5").unwrap(),
+            Predicate::parse("seismic_activity", "between:1,2").unwrap(),
+        ];
+
+        assert!(matches_all(&predicates, &stats(1.5, 20.0, 0.7)));
+        assert!(!matches_all(&predicates, &stats(2.5, 20.0, 0.7)));
+        assert!(!matches_all(&predicates, &stats(1.5, 20.0, 0.5)));
+        assert!(matches_all(&[], &stats(0.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_predicate_proto_roundtrip() {
+        let predicate = Predicate::parse("temperature_c", "lte:-12.5").unwrap();
+        assert_eq!(Predicate::from_proto(&predicate.to_proto()).unwrap(), predicate);
+        assert!(matches!(Predicate::parse("humidity", "gt:1"), Err(ShardError::InvalidInput(_))));
+    }
+}
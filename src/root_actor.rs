@@ -1,11 +1,17 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
 use std::sync::Arc;
 use actix::prelude::*;
+use futures::future::try_join_all;
 use log::info;
 use rand::seq::{IndexedRandom, SliceRandom};
 use tokio::sync::oneshot;
 
-use crate::location_actor::LocationActor;
+use crate::conn_manager::ChannelManager;
+use crate::dto::{EnrichedLocationStats, IfMatch, ExtendedLocationStats, LocationPatch, LocationStats, PoolStats, ShardError};
+use crate::location_actor::{DeleteLocation, GetLocation, LocationActor, PatchLocation, PutLocation, RewriteShards};
+use crate::trace::TraceContext;
+use crate::util::get_owner_node_id;
 
 const INITIAL_POOL_SIZE: usize = 15000;
 const POOL_REFRESH_THRESHOLD: f32 = 0.3; 
@@ -14,9 +20,11 @@ const ARBITER_POOL_SIZE: usize = 2;
 
 pub struct RootActor {
     addrs: HashMap<String, Addr<LocationActor>>,
+    owned: BTreeSet<String>, // locations this node holds the owner record for, kept sorted for listing
     pool: VecDeque<Addr<LocationActor>>,
     refresh_actor: Addr<PoolRefreshActor>,
     is_refreshing: bool,
+    warmed_up: bool,
 }
 
 
@@ -101,9 +109,11 @@ impl RootActor {
 
         let root_actor = RootActor {
             addrs: HashMap::new(),
+            owned: BTreeSet::new(),
             pool: VecDeque::with_capacity(INITIAL_POOL_SIZE),
             refresh_actor,
             is_refreshing: true, 
+            warmed_up: false,
         };
 
         
@@ -149,6 +159,7 @@ impl Handler<PoolRefreshComplete> for RootActor {
     fn handle(&mut self, msg: PoolRefreshComplete, _: &mut SyncContext<Self>) -> Self::Result {
         if msg.is_initial_warmup {
             info!("Initial pool warmup completed with {} actors", msg.new_actors.len());
+            self.warmed_up = true;
         } else {
             info!("Pool refresh completed with {} new actors", msg.new_actors.len());
         }
@@ -194,3 +205,183 @@ impl Handler<GetAddr> for RootActor {
         Ok(addr)
     }
 }
+
+// Like GetAddr, but never creates an actor for an unknown location
+#[derive(Message)]
+#[rtype(result = "Option<Addr<LocationActor>>")]
+pub struct FindAddr(pub String);
+
+impl Handler<FindAddr> for RootActor {
+    type Result = Option<Addr<LocationActor>>;
+
+    fn handle(&mut self, msg: FindAddr, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.addrs.get(&msg.0).cloned()
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MarkOwned(pub String);
+
+impl Handler<MarkOwned> for RootActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: MarkOwned, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.owned.insert(msg.0);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disown(pub String);
+
+impl Handler<Disown> for RootActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disown, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.owned.remove(&msg.0);
+    }
+}
+
+// Owned location ids sorting after `after`, in order
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct ListOwned {
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+impl Handler<ListOwned> for RootActor {
+    type Result = MessageResult<ListOwned>;
+
+    fn handle(&mut self, msg: ListOwned, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let ids = match &msg.after {
+            Some(after) => self.owned
+                .range::<String, _>((Bound::Excluded(after), Bound::Unbounded))
+                .take(msg.limit)
+                .cloned()
+                .collect(),
+            None => self.owned.iter().take(msg.limit).cloned().collect(),
+        };
+        MessageResult(ids)
+    }
+}
+
+// Like ListOwned, with the actor holding each record
+#[derive(Message)]
+#[rtype(result = "Vec<(String, Addr<LocationActor>)>")]
+pub struct ListOwnedAddrs {
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+impl Handler<ListOwnedAddrs> for RootActor {
+    type Result = MessageResult<ListOwnedAddrs>;
+
+    fn handle(&mut self, msg: ListOwnedAddrs, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let ids: Box<dyn Iterator<Item = &String>> = match &msg.after {
+            Some(after) => Box::new(self.owned.range::<String, _>((Bound::Excluded(after), Bound::Unbounded))),
+            None => Box::new(self.owned.iter()),
+        };
+        let addrs = ids
+            .filter_map(|id| self.addrs.get(id).map(|addr| (id.clone(), addr.clone())))
+            .take(msg.limit)
+            .collect();
+        MessageResult(addrs)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "PoolStats")]
+pub struct GetPoolStats;
+
+impl Handler<GetPoolStats> for RootActor {
+    type Result = MessageResult<GetPoolStats>;
+
+    fn handle(&mut self, _msg: GetPoolStats, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        MessageResult(PoolStats {
+            pool_size: self.pool.len(),
+            locations: self.addrs.len(),
+            owned: self.owned.len(),
+            is_refreshing: self.is_refreshing,
+            warmed_up: self.warmed_up,
+        })
+    }
+}
+
+// Root actors are sharded by owner node id across the pool
+pub fn root_actor_for<'a>(pool: &'a [Addr<RootActor>], location_id: &str) -> Result<&'a Addr<RootActor>, ShardError> {
+    if pool.is_empty() {
+        return Err(ShardError::ActixError("root actor pool is empty".to_owned()));
+    }
+    Ok(&pool[get_owner_node_id(location_id.to_owned()) as usize % pool.len()])
+}
+
+pub async fn lookup(pool: &[Addr<RootActor>], location_id: &str) -> Result<Addr<LocationActor>, ShardError> {
+    root_actor_for(pool, location_id)?
+        .send(GetAddr(location_id.to_owned()))
+        .await?
+        .map_err(|_| ShardError::ActixError(format!("No actor for location {}", location_id)))
+}
+
+// Writes a location this node owns and fans its shards out to the peers.
+// With `expected` set the write only applies if the location passes that If-Match condition.
+pub async fn put_location(pool: &[Addr<RootActor>], channel_manager: Arc<Addr<ChannelManager>>, location_id: String, stats: LocationStats, expected: Option<IfMatch>, trace: TraceContext) -> Result<EnrichedLocationStats, ShardError> {
+    let addr = lookup(pool, &location_id).await?;
+    let result = addr.send(PutLocation(ExtendedLocationStats::from_basic(location_id.clone(), stats), channel_manager, trace, expected)).await?;
+    // The record is committed before its shards go out, so it's owned even when the
+    // fan-out failed; only a rejected write leaves nothing behind
+    if result.is_ok() || addr.send(GetLocation).await?.is_ok() {
+        root_actor_for(pool, &location_id)?.do_send(MarkOwned(location_id));
+    }
+    result
+}
+
+// Merges a partial update into a location this node owns and re-encodes its shards
+pub async fn patch_location(pool: &[Addr<RootActor>], channel_manager: Arc<Addr<ChannelManager>>, location_id: String, patch: LocationPatch, expected: Option<IfMatch>, trace: TraceContext) -> Result<EnrichedLocationStats, ShardError> {
+    let addr = lookup(pool, &location_id).await?;
+    addr.send(PatchLocation(location_id, patch, channel_manager, trace, expected)).await?
+}
+
+// Deletes a location this node owns along with the peers' shards of it
+pub async fn delete_location(pool: &[Addr<RootActor>], channel_manager: Arc<Addr<ChannelManager>>, location_id: String, trace: TraceContext) -> Result<(), ShardError> {
+    let root_actor = root_actor_for(pool, &location_id)?;
+    let addr = root_actor
+        .send(FindAddr(location_id.clone()))
+        .await?
+        .ok_or_else(|| ShardError::NotFoundError(format!("Location {} not found", location_id)))?;
+    // A failed delete puts the record back, so only drop the id once it's gone
+    let result = addr.send(DeleteLocation(location_id.clone(), channel_manager, trace)).await?;
+    if matches!(result, Ok(()) | Err(ShardError::NotFoundError(_))) {
+        root_actor.do_send(Disown(location_id));
+    }
+    result
+}
+
+// Writes the shards of a location this node owns again from its record
+pub async fn rewrite_shards(pool: &[Addr<RootActor>], channel_manager: Arc<Addr<ChannelManager>>, location_id: String, trace: TraceContext) -> Result<(), ShardError> {
+    let addr = root_actor_for(pool, &location_id)?
+        .send(FindAddr(location_id.clone()))
+        .await?
+        .ok_or_else(|| ShardError::NotFoundError(format!("Location {} not found", location_id)))?;
+    addr.send(RewriteShards(location_id, channel_manager, trace)).await?
+}
+
+// Lists owned location ids across the whole pool, merged into one sorted page
+pub async fn list_owned(pool: &[Addr<RootActor>], after: Option<String>, limit: usize) -> Result<Vec<String>, ShardError> {
+    let pages = try_join_all(pool.iter().map(|root_actor| root_actor.send(ListOwned { after: after.clone(), limit }))).await?;
+    let mut ids: Vec<String> = pages.into_iter().flatten().collect();
+    ids.sort();
+    ids.truncate(limit);
+    Ok(ids)
+}
+
+// Reads the owner record of a location held by this node
+pub async fn get_location(pool: &[Addr<RootActor>], location_id: &str) -> Result<EnrichedLocationStats, ShardError> {
+    let not_found = || ShardError::NotFoundError(format!("Location {} not found", location_id));
+    let addr = root_actor_for(pool, location_id)?
+        .send(FindAddr(location_id.to_owned()))
+        .await?
+        .ok_or_else(not_found)?;
+    addr.send(GetLocation).await?.map_err(|_| not_found())
+}
@@ -0,0 +1,224 @@
+use std::env;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use actix_web::HttpRequest;
+use log::warn;
+use tonic::{Code, Request, Status};
+
+use crate::trace::{self, TraceContext};
+
+// Clients may ask for a tighter budget than the configured one
+pub const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout-ms";
+
+static CONFIG: OnceLock<RpcConfig> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RpcKind {
+    // getShardRequest
+    ShardRead,
+    // writeShardRequest, writeShardBatch, deleteShard; never retried
+    ShardWrite,
+    // routeWrite and the other calls forwarded to an owner
+    Route,
+}
+
+#[derive(Clone, Debug)]
+pub struct RpcConfig {
+    pub shard_read_timeout: Duration,
+    pub shard_write_timeout: Duration,
+    pub route_timeout: Duration,
+    // Extra attempts for idempotent calls, on top of the first one
+    pub max_retries: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    // Budget of a client request that doesn't bring its own
+    pub request_timeout: Duration,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            shard_read_timeout: Duration::from_secs(2),
+            shard_write_timeout: Duration::from_secs(2),
+            route_timeout: Duration::from_secs(5),
+            max_retries: 2,
+            backoff_base: Duration::from_millis(20),
+            backoff_max: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+fn millis_from_env(key: &str, default: Duration) -> Duration {
+    match env::var(key) {
+        Ok(value) => value.trim().parse().map(Duration::from_millis).unwrap_or_else(|_| {
+            warn!("Ignoring invalid {}={}, expected milliseconds", key, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+impl RpcConfig {
+    pub fn from_env() -> Self {
+        let default = RpcConfig::default();
+        RpcConfig {
+            shard_read_timeout: millis_from_env("RPC_SHARD_READ_TIMEOUT_MS", default.shard_read_timeout),
+            shard_write_timeout: millis_from_env("RPC_SHARD_WRITE_TIMEOUT_MS", default.shard_write_timeout),
+            route_timeout: millis_from_env("RPC_ROUTE_TIMEOUT_MS", default.route_timeout),
+            max_retries: env::var("RPC_MAX_RETRIES")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(default.max_retries),
+            backoff_base: millis_from_env("RPC_BACKOFF_BASE_MS", default.backoff_base),
+            backoff_max: millis_from_env("RPC_BACKOFF_MAX_MS", default.backoff_max),
+            request_timeout: millis_from_env("REQUEST_TIMEOUT_MS", default.request_timeout),
+        }
+    }
+
+    fn timeout(&self, kind: RpcKind) -> Duration {
+        match kind {
+            RpcKind::ShardRead => self.shard_read_timeout,
+            RpcKind::ShardWrite => self.shard_write_timeout,
+            RpcKind::Route => self.route_timeout,
+        }
+    }
+}
+
+pub fn init(config: RpcConfig) {
+    if CONFIG.set(config).is_err() {
+        warn!("RPC config already initialised");
+    }
+}
+
+fn config() -> &'static RpcConfig {
+    CONFIG.get_or_init(RpcConfig::default)
+}
+
+// Deadline of an HTTP request, from x-request-timeout-ms or the configured budget
+pub fn http_deadline(req: &HttpRequest) -> Instant {
+    let requested = req.headers()
+        .get(REQUEST_TIMEOUT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_millis);
+    Instant::now() + requested.unwrap_or(config().request_timeout).min(config().request_timeout)
+}
+
+// Deadline of a client gRPC call, from its grpc-timeout or the configured budget
+pub fn grpc_deadline<T>(request: &Request<T>) -> Instant {
+    let requested = trace::grpc_timeout(request);
+    Instant::now() + requested.unwrap_or(config().request_timeout).min(config().request_timeout)
+}
+
+// Upper bound of the wait before retry `attempt` (1-based), doubling from the base
+fn backoff_cap(attempt: u32, base: Duration, max: Duration) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(max)
+}
+
+// Full jitter, so peers retrying after the same failure spread out
+fn backoff(attempt: u32) -> Duration {
+    let cap = backoff_cap(attempt, config().backoff_base, config().backoff_max);
+    Duration::from_millis(rand::random_range(0..=cap.as_millis() as u64))
+}
+
+// Failures where the peer may not have seen the call, or may answer the next one
+fn is_retryable(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted)
+}
+
+// Runs `call` with a per-attempt timeout that never outlasts the trace's deadline. The
+// closure gets that timeout to set on its request so the peer can stop early too.
+// Idempotent calls are retried with backoff while attempts and budget are left;
+// anything else gets exactly one attempt.
+pub async fn call<T, F, Fut>(kind: RpcKind, trace: &TraceContext, idempotent: bool, mut call: F) -> Result<T, Status>
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+{
+    let attempts = if idempotent { config().max_retries + 1 } else { 1 };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let timeout = match trace.remaining() {
+            Some(remaining) if remaining.is_zero() => return Err(Status::deadline_exceeded("request deadline passed")),
+            Some(remaining) => remaining.min(config().timeout(kind)),
+            None => config().timeout(kind),
+        };
+
+        let result = match tokio::time::timeout(timeout, call(timeout)).await {
+            Ok(result) => result,
+            Err(_) => Err(Status::deadline_exceeded(format!("no answer within {}ms", timeout.as_millis()))),
+        };
+
+        match result {
+            Err(status) if attempt < attempts && is_retryable(&status) => {
+                let wait = backoff(attempt);
+                if trace.remaining().is_some_and(|remaining| remaining <= wait) {
+                    return Err(status);
+                }
+                warn!("{:?} call failed on attempt {} of {}, retrying in {}ms: {}", kind, attempt, attempts, wait.as_millis(), status);
+                tokio::time::sleep(wait).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_backoff_cap() {
+        let (base, max) = (Duration::from_millis(20), Duration::from_millis(500));
+        assert_eq!(backoff_cap(1, base, max), Duration::from_millis(20));
+        assert_eq!(backoff_cap(2, base, max), Duration::from_millis(40));
+        assert_eq!(backoff_cap(4, base, max), Duration::from_millis(160));
+        assert_eq!(backoff_cap(6, base, max), max);
+        assert_eq!(backoff_cap(64, base, max), max);
+    }
+
+    #[actix_rt::test]
+    async fn test_retries_only_idempotent_calls() {
+        let calls = Cell::new(0);
+        let flaky = |_: Duration| {
+            calls.set(calls.get() + 1);
+            let attempt = calls.get();
+            async move { if attempt < 3 { Err(Status::unavailable("down")) } else { Ok(attempt) } }
+        };
+        let trace = TraceContext::new_root();
+
+        assert_eq!(call(RpcKind::ShardRead, &trace, true, flaky).await.unwrap(), 3);
+
+        calls.set(0);
+        assert_eq!(call(RpcKind::Route, &trace, false, flaky).await.unwrap_err().code(), Code::Unavailable);
+        assert_eq!(calls.get(), 1);
+
+        calls.set(0);
+        let rejected = call(RpcKind::ShardRead, &trace, true, |_| {
+            calls.set(calls.get() + 1);
+            async { Err::<(), _>(Status::failed_precondition("stale")) }
+        }).await;
+        assert_eq!(rejected.unwrap_err().code(), Code::FailedPrecondition);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_bounds_attempts() {
+        let mut trace = TraceContext::new_root();
+        trace.deadline = Some(Instant::now());
+        let result = call(RpcKind::ShardWrite, &trace, true, |_| async { Ok(()) }).await;
+        assert_eq!(result.unwrap_err().code(), Code::DeadlineExceeded);
+
+        trace.deadline = Some(Instant::now() + Duration::from_millis(50));
+        let result = call(RpcKind::ShardWrite, &trace, true, |timeout| async move {
+            assert!(timeout <= Duration::from_millis(50));
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        }).await;
+        assert_eq!(result.unwrap_err().code(), Code::DeadlineExceeded);
+    }
+}
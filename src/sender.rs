@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use log::warn;
+use tonic::metadata::MetadataValue;
+use tonic::Request;
+
+use crate::dto::ShardError;
+use crate::protocol::{self, SENDER_HEADER_VERSION};
+use crate::tls;
+use crate::util::get_owner_node_id;
+
+// Shard writes name their sender here; with mTLS the certificate has to back the claim
+pub const NODE_ID_HEADER: &str = "x-rs-node-id";
+
+static CURRENT_NODE: OnceLock<u32> = OnceLock::new();
+static REJECTED: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    // No usable sender node id
+    Unauthenticated,
+    // The claimed node id isn't one the TLS certificate names
+    Spoofed,
+    // A shard write or delete from a node other than the location's owner
+    NotOwner,
+    // A routed write, or a shard, for a location this node shouldn't get
+    Misrouted,
+}
+
+impl Rejection {
+    const ALL: [Rejection; 4] = [Rejection::Unauthenticated, Rejection::Spoofed, Rejection::NotOwner, Rejection::Misrouted];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Rejection::Unauthenticated => "unauthenticated",
+            Rejection::Spoofed => "spoofed",
+            Rejection::NotOwner => "not_owner",
+            Rejection::Misrouted => "misrouted",
+        }
+    }
+}
+
+fn reject(rejection: Rejection, rpc: &str, message: String) -> ShardError {
+    REJECTED[rejection as usize].fetch_add(1, Ordering::Relaxed);
+    warn!("Rejected {} ({}): {}", rpc, rejection.as_str(), message);
+    match rejection {
+        Rejection::Unauthenticated => ShardError::Unauthenticated(message),
+        Rejection::Spoofed | Rejection::NotOwner => ShardError::PermissionDenied(message),
+        Rejection::Misrouted => ShardError::Misrouted(message),
+    }
+}
+
+// Rejected node RPCs since startup, by reason
+pub fn rejections() -> BTreeMap<String, u64> {
+    Rejection::ALL.iter()
+        .map(|rejection| (rejection.as_str().to_owned(), REJECTED[*rejection as usize].load(Ordering::Relaxed)))
+        .collect()
+}
+
+pub fn init(current_node: u32) {
+    if CURRENT_NODE.set(current_node).is_err() {
+        warn!("Sender node id already initialised");
+    }
+}
+
+// Names this node as the sender of an outgoing request
+pub fn stamp<T>(request: &mut Request<T>) {
+    if let Some(node_id) = CURRENT_NODE.get() {
+        request.metadata_mut().insert(NODE_ID_HEADER, MetadataValue::from(*node_id));
+    }
+}
+
+// Shard writes that don't name their sender are let in while a peer's last handshake
+// reported a protocol from before the header
+fn legacy_peers() -> bool {
+    protocol::any_peer_below(SENDER_HEADER_VERSION)
+}
+
+// The node a request comes from. Without TLS the header is taken at its word, which
+// still catches misconfigured peers; with TLS it must match the certificate.
+// None for an unnamed request let in because a peer still runs a legacy protocol.
+pub fn sender<T>(request: &Request<T>, rpc: &str) -> Result<Option<u32>, ShardError> {
+    identify(request, rpc, legacy_peers())
+}
+
+fn identify<T>(request: &Request<T>, rpc: &str, legacy_peers: bool) -> Result<Option<u32>, ShardError> {
+    let claimed = match request.metadata().get(NODE_ID_HEADER) {
+        Some(value) => Some(value.to_str().ok()
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .ok_or_else(|| reject(Rejection::Unauthenticated, rpc, format!("malformed {} header", NODE_ID_HEADER)))?),
+        None => None,
+    };
+    let certified = tls::peer_identity(request).map(|identity| identity.node_ids.as_slice());
+
+    match (claimed, certified) {
+        (Some(node_id), Some(node_ids)) if node_ids.contains(&node_id) => Ok(Some(node_id)),
+        (Some(node_id), Some(_)) => Err(reject(Rejection::Spoofed, rpc, format!("claims to be node {} but its certificate doesn't name it", node_id))),
+        (Some(node_id), None) => Ok(Some(node_id)),
+        (None, Some([node_id])) => Ok(Some(*node_id)),
+        (None, None) if legacy_peers => Ok(None),
+        (None, _) => Err(reject(Rejection::Unauthenticated, rpc, format!("no {} header", NODE_ID_HEADER))),
+    }
+}
+
+// Only the owner writes or drops the shards of a location, and keeps none itself.
+// An unnamed legacy sender can only be checked for the latter.
+pub fn authorize_shard(sender: Option<u32>, current_node: u32, location_id: &str, rpc: &str) -> Result<(), ShardError> {
+    let owner_id = get_owner_node_id(location_id.to_owned());
+    if owner_id == current_node {
+        return Err(reject(Rejection::Misrouted, rpc, format!("{} is owned by this node, which holds no shard of it", location_id)));
+    }
+    if let Some(sender) = sender.filter(|sender| *sender != owner_id) {
+        return Err(reject(Rejection::NotOwner, rpc, format!("node {} sent a shard of {}, which node {} owns", sender, location_id, owner_id)));
+    }
+    Ok(())
+}
+
+// Routed writes have to land on the location's owner
+pub fn authorize_route(current_node: u32, location_id: &str, rpc: &str) -> Result<(), ShardError> {
+    let owner_id = get_owner_node_id(location_id.to_owned());
+    if owner_id != current_node {
+        return Err(reject(Rejection::Misrouted, rpc, format!("{} is owned by node {}, not this node", location_id, owner_id)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned_by(node_id: u32) -> String {
+        (0..).map(|i| format!("loc-{}", i)).find(|id| get_owner_node_id(id.clone()) == node_id).unwrap()
+    }
+
+    #[test]
+    fn test_sender_header() {
+        let mut request = Request::new(());
+        assert!(matches!(identify(&request, "test", false), Err(ShardError::Unauthenticated(_))));
+
+        request.metadata_mut().insert(NODE_ID_HEADER, MetadataValue::from(3u32));
+        assert_eq!(identify(&request, "test", false).unwrap(), Some(3));
+
+        request.metadata_mut().insert(NODE_ID_HEADER, MetadataValue::from_static("three"));
+        assert!(matches!(identify(&request, "test", false), Err(ShardError::Unauthenticated(_))));
+    }
+
+    #[test]
+    fn test_legacy_peer_may_omit_sender() {
+        assert_eq!(identify(&Request::new(()), "test", true).unwrap(), None);
+        let location_id = owned_by(2);
+        assert!(authorize_shard(None, 0, &location_id, "test").is_ok());
+        assert!(matches!(authorize_shard(None, 2, &location_id, "test"), Err(ShardError::Misrouted(_))));
+    }
+
+    #[test]
+    fn test_authorize() {
+        let location_id = owned_by(2);
+        assert!(authorize_shard(Some(2), 0, &location_id, "test").is_ok());
+        assert!(matches!(authorize_shard(Some(1), 0, &location_id, "test"), Err(ShardError::PermissionDenied(_))));
+        assert!(matches!(authorize_shard(Some(2), 2, &location_id, "test"), Err(ShardError::Misrouted(_))));
+
+        assert!(authorize_route(2, &location_id, "test").is_ok());
+        assert!(matches!(authorize_route(0, &location_id, "test"), Err(ShardError::Misrouted(_))));
+        assert!(rejections()["misrouted"] >= 2);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use actix::prelude::*;
+use futures::future::join_all;
+use log::{error, info};
+use tokio::sync::oneshot;
+use tonic::transport::Channel;
+use tonic::Request;
+
+use crate::conn_manager::ChannelManager;
+use crate::constants::{MAX_SHARD_BATCH, SHARD_BATCH_WINDOW};
+use crate::coordinator::record_rpc_failure;
+use crate::dto::ShardError;
+use crate::node::from_write_result;
+use crate::protocol::{self, SHARD_BATCH_VERSION};
+use crate::rpc::{self, RpcKind};
+use crate::rs::rs::rs_client::RsClient;
+use crate::sender;
+use crate::rs::rs::{WriteShardBatchRequest, WriteShardRequest};
+use crate::trace::{Span, SpanKind, TraceContext};
+
+static BATCHER: OnceLock<Addr<ShardBatcher>> = OnceLock::new();
+
+struct PendingShard {
+    location_id: String,
+    shard: Vec<u8>,
+    trace: TraceContext,
+    ack: oneshot::Sender<Result<(), ShardError>>,
+}
+
+struct PendingBatch {
+    generation: u64,
+    channel: Channel,
+    shards: Vec<PendingShard>,
+}
+
+// Coalesces shard writes per destination node over a short window and sends each
+// node's share as one writeShardBatch call, acking every entry on its own
+pub struct ShardBatcher {
+    channel_manager: Addr<ChannelManager>,
+    pending: HashMap<u32, PendingBatch>,
+    // Tags each batch so a window timer only flushes the batch that armed it
+    next_generation: u64,
+}
+
+impl ShardBatcher {
+    pub fn new(channel_manager: Addr<ChannelManager>) -> Self {
+        ShardBatcher {
+            channel_manager,
+            pending: HashMap::new(),
+            next_generation: 0,
+        }
+    }
+
+    fn flush(&mut self, node_id: u32) {
+        let Some(batch) = self.pending.remove(&node_id) else {
+            return;
+        };
+        actix::spawn(send_batch(self.channel_manager.clone(), node_id, batch));
+    }
+
+    // A full batch may have gone out already, its timer must not cut a newer batch short
+    fn flush_generation(&mut self, node_id: u32, generation: u64) {
+        if self.pending.get(&node_id).is_some_and(|batch| batch.generation == generation) {
+            self.flush(node_id);
+        }
+    }
+}
+
+impl Actor for ShardBatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("ShardBatcher started");
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "oneshot::Receiver<Result<(), ShardError>>")]
+pub struct QueueShard {
+    pub node_id: u32,
+    pub channel: Channel,
+    pub location_id: String,
+    pub shard: Vec<u8>,
+    pub trace: TraceContext,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Flush {
+    node_id: u32,
+    generation: u64,
+}
+
+impl Handler<QueueShard> for ShardBatcher {
+    type Result = MessageResult<QueueShard>;
+
+    fn handle(&mut self, msg: QueueShard, ctx: &mut Context<Self>) -> Self::Result {
+        let (ack, rx) = oneshot::channel();
+        let next_generation = &mut self.next_generation;
+        let batch = self.pending.entry(msg.node_id).or_insert_with(|| {
+            let generation = *next_generation;
+            *next_generation += 1;
+            ctx.notify_later(Flush { node_id: msg.node_id, generation }, SHARD_BATCH_WINDOW);
+            PendingBatch { generation, channel: msg.channel, shards: Vec::new() }
+        });
+        batch.shards.push(PendingShard { location_id: msg.location_id, shard: msg.shard, trace: msg.trace, ack });
+
+        if batch.shards.len() >= MAX_SHARD_BATCH {
+            self.flush(msg.node_id);
+        }
+        MessageResult(rx)
+    }
+}
+
+impl Handler<Flush> for ShardBatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: Flush, _ctx: &mut Context<Self>) -> Self::Result {
+        self.flush_generation(msg.node_id, msg.generation);
+    }
+}
+
+async fn send_batch(channel_manager: Addr<ChannelManager>, node_id: u32, batch: PendingBatch) {
+    let client = RsClient::new(batch.channel);
+    // Peers on protocol 1 predate writeShardBatch, they get one call per shard
+    if protocol::peer_version(node_id).is_some_and(|version| version < SHARD_BATCH_VERSION) {
+        send_each(channel_manager, node_id, client, batch.shards).await;
+        return;
+    }
+
+    let mut span = Span::start("rpc.write_shard_batch", batch.shards.first().map(|shard| &shard.trace), SpanKind::Client);
+    // The call can't outlive the entry with the least time left
+    for deadline in batch.shards.iter().filter_map(|shard| shard.trace.deadline) {
+        span.set_deadline(deadline);
+    }
+    span.set_attribute("peer.node_id", node_id);
+    span.set_attribute("batch.size", batch.shards.len());
+
+    let (acks, shards): (Vec<_>, Vec<_>) = batch.shards.into_iter()
+        .map(|pending| ((pending.location_id.clone(), pending.ack), WriteShardRequest { location_id: pending.location_id, shard: pending.shard }))
+        .unzip();
+    // Entries overwrite unconditionally, so a resent batch could undo newer writes. With
+    // a single attempt the payloads move into the request instead of being copied.
+    let mut shards = Some(shards);
+    let response = rpc::call(RpcKind::ShardWrite, &span.context(), false, |timeout| {
+        let mut client = client.clone();
+        let mut request = Request::new(WriteShardBatchRequest { shards: shards.take().unwrap_or_default() });
+        span.context().inject(&mut request);
+        sender::stamp(&mut request);
+        request.set_timeout(timeout);
+        async move { client.write_shard_batch(request).await }
+    }).await;
+
+    match response {
+        Ok(res) => {
+            let mut results = res.into_inner().results.into_iter();
+            for (location_id, ack) in acks {
+                let result = results.next()
+                    .ok_or_else(|| ShardError::RpcError(format!("No shard ack for {} from node {}", location_id, node_id)))
+                    .and_then(|result| from_write_result(&result))
+                    .map_err(|err| ShardError::RpcError(format!("Failed to write shard: {}", err)));
+                ack.send(result).ok();
+            }
+        }
+        Err(status) => {
+            record_rpc_failure(&channel_manager, node_id, &status);
+            span.set_error(&status);
+            error!("Failed to write batch of {} shards to node {}: {}", acks.len(), node_id, status);
+            for (_, ack) in acks {
+                ack.send(Err(ShardError::RpcError(format!("Failed to write shard: {}", status)))).ok();
+            }
+        }
+    }
+}
+
+async fn send_each(channel_manager: Addr<ChannelManager>, node_id: u32, client: RsClient<Channel>, shards: Vec<PendingShard>) {
+    let writes = shards.into_iter().map(|pending| {
+        let client = client.clone();
+        let channel_manager = channel_manager.clone();
+        async move {
+            // Sent once, like the batch, so the payload moves into the request
+            let mut shard = Some(WriteShardRequest { location_id: pending.location_id, shard: pending.shard });
+            let result = rpc::call(RpcKind::ShardWrite, &pending.trace, false, |timeout| {
+                let mut client = client.clone();
+                let mut request = Request::new(shard.take().unwrap_or_default());
+                pending.trace.inject(&mut request);
+                sender::stamp(&mut request);
+                request.set_timeout(timeout);
+                async move { client.write_shard_request(request).await }
+            }).await;
+            if let Err(status) = &result {
+                record_rpc_failure(&channel_manager, node_id, status);
+            }
+            let result = result.map(|_| ()).map_err(|status| ShardError::RpcError(format!("Failed to write shard: {}", status)));
+            pending.ack.send(result).ok();
+        }
+    });
+    join_all(writes).await;
+}
+
+pub fn init(channel_manager: Addr<ChannelManager>) {
+    if BATCHER.set(ShardBatcher::new(channel_manager).start()).is_err() {
+        error!("Shard batcher already initialised");
+    }
+}
+
+pub fn is_running() -> bool {
+    BATCHER.get().is_some()
+}
+
+// Queues a shard for the next batch to its node and waits for that entry's ack
+pub async fn write_shard(node_id: u32, channel: Channel, location_id: String, shard: Vec<u8>, trace: TraceContext) -> Result<(), ShardError> {
+    let batcher = BATCHER.get().ok_or_else(|| ShardError::Unavailable("shard batcher is not running".to_owned()))?;
+    let ack = batcher.send(QueueShard { node_id, channel, location_id, shard, trace }).await?;
+    ack.await.map_err(|_| ShardError::RpcError(format!("Shard batch to node {} was dropped", node_id)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tonic::transport::Endpoint;
+
+    #[actix_rt::test]
+    async fn test_batch_failure_acks_every_entry() {
+        let channel_manager = ChannelManager::new(0, Arc::new(HashMap::new()), Duration::from_millis(300)).start();
+        let batcher = ShardBatcher::new(channel_manager).start();
+        // Nothing listens here, the batch RPC fails once the window closes
+        let channel = Endpoint::from_static("http://127.0.0.1:1").connect_lazy();
+
+        let mut acks = Vec::new();
+        for location_id in ["loc-1", "loc-2", "loc-3"] {
+            acks.push(batcher.send(QueueShard {
+                node_id: 1,
+                channel: channel.clone(),
+                location_id: location_id.to_owned(),
+                shard: vec![1, 2, 3],
+                trace: TraceContext::new_root(),
+            }).await.unwrap());
+        }
+
+        for ack in acks {
+            let result = tokio::time::timeout(Duration::from_secs(5), ack).await.unwrap().unwrap();
+            assert!(matches!(result, Err(ShardError::RpcError(_))));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_stale_flush_leaves_newer_batch() {
+        let channel_manager = ChannelManager::new(0, Arc::new(HashMap::new()), Duration::from_millis(300)).start();
+        let mut batcher = ShardBatcher::new(channel_manager);
+        let channel = Endpoint::from_static("http://127.0.0.1:1").connect_lazy();
+        // The batch from generation 0 filled up and went out, this one replaced it
+        batcher.pending.insert(1, PendingBatch { generation: 1, channel, shards: Vec::new() });
+
+        batcher.flush_generation(1, 0);
+        assert!(batcher.pending.contains_key(&1));
+
+        batcher.flush_generation(1, 1);
+        assert!(batcher.pending.is_empty());
+    }
+}
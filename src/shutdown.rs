@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info};
+use tokio::sync::Notify;
+
+struct DrainState {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+// Tracks in-flight writes so shutdown can wait for their shard fan-out to finish
+#[derive(Clone)]
+pub struct Drain {
+    state: Arc<DrainState>,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Drain {
+            state: Arc::new(DrainState {
+                draining: AtomicBool::new(false),
+                in_flight: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::SeqCst)
+    }
+
+    // Registers a write, or returns None once shutdown has started
+    pub fn enter(&self) -> Option<DrainGuard> {
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = DrainGuard { state: self.state.clone() };
+
+        if self.is_draining() {
+            return None;
+        }
+        Some(guard)
+    }
+
+    pub fn start(&self) {
+        self.state.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Returns false if writes were still running when the timeout expired
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.state.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+pub struct DrainGuard {
+    state: Arc<DrainState>,
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.state.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.state.idle.notify_waiters();
+        }
+    }
+}
+
+// Resolves on SIGINT or SIGTERM
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+                    _ = terminate.recv() => info!("Received SIGTERM"),
+                }
+                return;
+            }
+            Err(err) => error!("Failed to install SIGTERM handler: {}", err),
+        }
+    }
+
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for SIGINT: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_enter_is_refused_while_draining() {
+        let drain = Drain::new();
+        let guard = drain.enter();
+        assert!(guard.is_some());
+
+        drain.start();
+        assert!(drain.enter().is_none());
+        assert_eq!(drain.in_flight(), 1);
+
+        drop(guard);
+        assert_eq!(drain.in_flight(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_wait_idle_waits_for_in_flight_writes() {
+        let drain = Drain::new();
+        let guard = drain.enter().unwrap();
+        drain.start();
+
+        assert!(!drain.wait_idle(Duration::from_millis(50)).await);
+
+        actix_rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+
+        assert!(drain.wait_idle(Duration::from_secs(1)).await);
+    }
+}
@@ -0,0 +1,11 @@
+use crate::dto::EnrichedLocationStats;
+
+pub const STATS_ID: &str = "bac32c52-bb64-476d-a36d-91069bbd8a5e";
+
+pub fn stats(seismic_activity: f64, temperature_c: f64, radiation_level: f64) -> EnrichedLocationStats {
+    stats_at(1, seismic_activity, temperature_c, radiation_level)
+}
+
+pub fn stats_at(modification_count: i64, seismic_activity: f64, temperature_c: f64, radiation_level: f64) -> EnrichedLocationStats {
+    EnrichedLocationStats::new(STATS_ID.to_owned(), modification_count, seismic_activity, temperature_c, radiation_level).unwrap()
+}
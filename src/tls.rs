@@ -0,0 +1,320 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use futures::stream::{self, Stream};
+use hyper_util::rt::TokioIo;
+use log::{error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tonic::transport::server::Connected;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::{Request, Status};
+
+static TLS: OnceLock<Tls> = OnceLock::new();
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_BACKLOG: usize = 128;
+
+pub struct TlsPaths {
+    cert: PathBuf,
+    key: PathBuf,
+    ca: PathBuf,
+}
+
+impl TlsPaths {
+    // TLS_CERT, TLS_KEY and TLS_CA (PEM files) together turn on mTLS between nodes,
+    // none of them leaves gRPC in plaintext
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let vars = ["TLS_CERT", "TLS_KEY", "TLS_CA"].map(|key| env::var(key).ok().filter(|value| !value.trim().is_empty()));
+        match vars {
+            [None, None, None] => Ok(None),
+            [Some(cert), Some(key), Some(ca)] => Ok(Some(TlsPaths { cert: cert.into(), key: key.into(), ca: ca.into() })),
+            _ => Err("TLS_CERT, TLS_KEY and TLS_CA must be set together".to_owned()),
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [&self.cert, &self.key, &self.ca].iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+struct Loaded {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+struct Tls {
+    paths: TlsPaths,
+    // Host each configured node is reached at, which its certificate has to name
+    nodes: Vec<(u32, ServerName<'static>)>,
+    // Replaced whole on reload, so even a poisoned lock holds a complete config
+    current: RwLock<Loaded>,
+}
+
+// Nodes the client certificate of a connection is valid for; several nodes can share a host
+#[derive(Clone, Debug)]
+pub struct PeerIdentity {
+    pub remote_addr: SocketAddr,
+    pub node_ids: Vec<u32>,
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("{}: {}", path.display(), err))?
+        .ok_or_else(|| format!("{}: no private key found", path.display()))
+}
+
+// Both sides present the node certificate and trust only the configured CA. Client
+// certificates are optional at the TLS layer so public clients can reach
+// LocationService; the Rs service checks them itself.
+fn load(paths: &TlsPaths) -> Result<Loaded, String> {
+    let modified = paths.modified();
+    let certs = read_certs(&paths.cert)?;
+    let key = read_key(&paths.key)?;
+
+    let mut roots = RootCertStore::empty();
+    for ca in read_certs(&paths.ca)? {
+        roots.add(ca).map_err(|err| format!("{}: {}", paths.ca.display(), err))?;
+    }
+    let roots = Arc::new(roots);
+
+    let verifier = WebPkiClientVerifier::builder(roots.clone())
+        .allow_unauthenticated()
+        .build()
+        .map_err(|err| err.to_string())?;
+    let mut server = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs.clone(), key.clone_key())
+        .map_err(|err| err.to_string())?;
+    server.alpn_protocols = vec![b"h2".to_vec()];
+
+    let mut client = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+    client.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(Loaded { server: Arc::new(server), client: Arc::new(client), modified })
+}
+
+fn node_host(endpoint: &str) -> Option<ServerName<'static>> {
+    let host = match endpoint.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => endpoint.rsplit_once(':').map_or(endpoint, |(host, _)| host).to_owned(),
+    };
+    ServerName::try_from(host).ok()
+}
+
+impl Tls {
+    fn server_config(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).server.clone()
+    }
+
+    fn client_config(&self) -> Arc<ClientConfig> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).client.clone()
+    }
+
+    fn identify(&self, remote_addr: SocketAddr, cert: Option<&CertificateDer<'_>>) -> PeerIdentity {
+        let node_ids = cert
+            .and_then(|cert| webpki::EndEntityCert::try_from(cert).ok())
+            .map(|cert| self.nodes.iter()
+                .filter(|(_, host)| cert.verify_is_valid_for_subject_name(host).is_ok())
+                .map(|(node_id, _)| *node_id)
+                .collect())
+            .unwrap_or_default();
+        PeerIdentity { remote_addr, node_ids }
+    }
+
+    // Only new connections pick up reloaded certificates, established ones keep theirs
+    fn reload_if_changed(&self) -> Result<(), String> {
+        if self.paths.modified() == self.current.read().unwrap_or_else(PoisonError::into_inner).modified {
+            return Ok(());
+        }
+        let loaded = load(&self.paths)?;
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = loaded;
+        info!("Reloaded TLS certificates");
+        Ok(())
+    }
+}
+
+// Loads the certificates when TLS is configured; returns whether it is
+pub fn init(endpoints: &[String]) -> Result<bool, String> {
+    let Some(paths) = TlsPaths::from_env()? else {
+        return Ok(false);
+    };
+    let loaded = load(&paths)?;
+    let nodes = endpoints.iter()
+        .enumerate()
+        .filter_map(|(node_id, endpoint)| node_host(endpoint).map(|host| (node_id as u32, host)))
+        .collect();
+    if TLS.set(Tls { paths, nodes, current: RwLock::new(loaded) }).is_err() {
+        error!("TLS already initialised");
+    }
+    info!("Mutual TLS enabled for gRPC");
+    Ok(true)
+}
+
+pub fn is_enabled() -> bool {
+    TLS.get().is_some()
+}
+
+// Polls the certificate files and swaps in new configs when they change
+pub async fn watch() {
+    let Some(tls) = TLS.get() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = tls.reload_if_changed() {
+            error!("Failed to reload TLS certificates, keeping the current ones: {}", err);
+        }
+    }
+}
+
+// The URI keeps its http scheme, TLS happens inside the connector
+pub fn lazy_channel(endpoint: Endpoint) -> Channel {
+    let Some(tls) = TLS.get() else {
+        return endpoint.connect_lazy();
+    };
+    endpoint.connect_with_connector_lazy(tower::service_fn(move |uri: Uri| async move {
+        let host = uri.host().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no host in uri"))?.to_owned();
+        let port = uri.port_u16().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no port in uri"))?;
+        let name = ServerName::try_from(host.clone()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let tcp = TcpStream::connect((host.as_str(), port)).await?;
+        tcp.set_nodelay(true)?;
+        let stream = TlsConnector::from(tls.client_config()).connect(name, tcp).await?;
+        Ok::<_, io::Error>(TokioIo::new(stream))
+    }))
+}
+
+pub struct TlsConnection {
+    stream: TlsStream<TcpStream>,
+    identity: PeerIdentity,
+}
+
+impl Connected for TlsConnection {
+    type ConnectInfo = PeerIdentity;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.identity.clone()
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Accepts connections and runs their handshakes off the accept loop, so a slow
+// client can't hold up the others. Ends once the server drops the stream.
+pub fn incoming(listener: TcpListener) -> impl Stream<Item = Result<TlsConnection, io::Error>> {
+    let (tx, rx) = mpsc::channel(HANDSHAKE_BACKLOG);
+    tokio::spawn(async move {
+        let Some(tls) = TLS.get() else {
+            error!("TLS is not initialised, not accepting gRPC connections");
+            return;
+        };
+        while !tx.is_closed() {
+            let (tcp, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Failed to accept gRPC connection: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                tcp.set_nodelay(true).ok();
+                let acceptor = TlsAcceptor::from(tls.server_config());
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => {
+                        let identity = tls.identify(remote_addr, stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()));
+                        tx.send(Ok(TlsConnection { stream, identity })).await.ok();
+                    }
+                    Ok(Err(err)) => warn!("TLS handshake with {} failed: {}", remote_addr, err),
+                    Err(_) => warn!("TLS handshake with {} timed out", remote_addr),
+                }
+            });
+        }
+    });
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|connection| (connection, rx)) })
+}
+
+pub fn peer_identity<T>(request: &Request<T>) -> Option<&PeerIdentity> {
+    request.extensions().get::<PeerIdentity>()
+}
+
+// Interceptor for the node-to-node and admin services: with TLS on, only callers
+// presenting a certificate for one of the configured nodes get through
+pub fn require_node(request: Request<()>) -> Result<Request<()>, Status> {
+    if !is_enabled() {
+        return Ok(request);
+    }
+    match peer_identity(&request) {
+        Some(identity) if !identity.node_ids.is_empty() => Ok(request),
+        Some(identity) => {
+            warn!("Rejected node RPC from {}: certificate doesn't name a configured node", identity.remote_addr);
+            Err(Status::unauthenticated("client certificate doesn't belong to a configured node"))
+        }
+        None => Err(Status::unauthenticated("node RPCs require a client certificate")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_host() {
+        assert_eq!(node_host("127.0.0.1:8001"), ServerName::try_from("127.0.0.1").ok());
+        assert_eq!(node_host("rust-server-1"), ServerName::try_from("rust-server-1").ok());
+        assert_eq!(node_host("rust-server-2:8000"), ServerName::try_from("rust-server-2").ok());
+        assert!(node_host("not a host:80").is_none());
+    }
+}
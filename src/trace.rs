@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use actix::prelude::*;
+use actix_web::HttpRequest;
+use awc::Client;
+use futures::future::{self, LocalBoxFuture};
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+use tonic::metadata::MetadataValue;
+use tonic::Request;
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_BATCH_SIZE: usize = 512;
+const DEFAULT_TRACE_FILE: &str = "spans.jsonl";
+const DEFAULT_OTLP_ENDPOINT: &str = "http://127.0.0.1:4318/v1/traces";
+
+static EXPORTER: OnceLock<Addr<SpanExporter>> = OnceLock::new();
+
+// W3C trace context carried across HTTP and gRPC hops
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    // When the caller gives up on the request; outgoing calls only get what is left
+    pub deadline: Option<Instant>,
+}
+
+impl TraceContext {
+    #[cfg(test)]
+    pub fn new_root() -> Self {
+        TraceContext {
+            trace_id: new_trace_id(),
+            span_id: new_span_id(),
+            deadline: None,
+        }
+    }
+
+    // Parses `00-<trace_id>-<span_id>-<flags>`
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() != 4 || parts[0].len() != 2 || parts[3].len() != 2 {
+            return None;
+        }
+
+        let (trace_id, span_id) = (parts[1], parts[2]);
+        if !is_hex_id(trace_id, 32) || !is_hex_id(span_id, 16) {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id: trace_id.to_lowercase(),
+            span_id: span_id.to_lowercase(),
+            deadline: None,
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+
+    pub fn from_http(req: &HttpRequest) -> Option<Self> {
+        req.headers()
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::from_traceparent)
+    }
+
+    // Peers send their remaining budget as grpc-timeout along with the traceparent
+    pub fn from_metadata<T>(request: &Request<T>) -> Option<Self> {
+        let mut ctx = request.metadata()
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::from_traceparent)?;
+        ctx.deadline = grpc_timeout(request).map(|timeout| Instant::now() + timeout);
+        Some(ctx)
+    }
+
+    pub fn inject<T>(&self, request: &mut Request<T>) {
+        if let Ok(value) = MetadataValue::try_from(self.to_traceparent()) {
+            request.metadata_mut().insert(TRACEPARENT_HEADER, value);
+        }
+        if let Some(remaining) = self.remaining() {
+            request.set_timeout(remaining);
+        }
+    }
+
+    // Time left until the deadline, zero once it has passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+pub fn grpc_timeout<T>(request: &Request<T>) -> Option<Duration> {
+    request.metadata()
+        .get(GRPC_TIMEOUT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_grpc_timeout)
+}
+
+// Parses `<digits><unit>` with units H, M, S, m (millis), u (micros) and n (nanos)
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = digits.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+fn is_hex_id(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value.chars().all(|c| c.is_ascii_hexdigit())
+        && value.chars().any(|c| c != '0')
+}
+
+fn new_trace_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn new_span_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanKind {
+    Internal,
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    fn otlp_code(&self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+// A span is exported when it is dropped, so early returns are still recorded
+pub struct Span {
+    name: &'static str,
+    kind: SpanKind,
+    context: TraceContext,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    attributes: BTreeMap<&'static str, String>,
+    error: Option<String>,
+}
+
+impl Span {
+    pub fn start(name: &'static str, parent: Option<&TraceContext>, kind: SpanKind) -> Self {
+        let trace_id = parent
+            .map(|ctx| ctx.trace_id.clone())
+            .unwrap_or_else(new_trace_id);
+
+        Span {
+            name,
+            kind,
+            context: TraceContext { trace_id, span_id: new_span_id(), deadline: parent.and_then(|ctx| ctx.deadline) },
+            parent_span_id: parent.map(|ctx| ctx.span_id.clone()),
+            start: SystemTime::now(),
+            attributes: BTreeMap::new(),
+            error: None,
+        }
+    }
+
+    pub fn context(&self) -> TraceContext {
+        self.context.clone()
+    }
+
+    // Tightens the deadline handed on to child spans, an earlier one is kept
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.context.deadline = Some(self.context.deadline.map_or(deadline, |current| current.min(deadline)));
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        self.attributes.insert(key, value.to_string());
+    }
+
+    pub fn set_error(&mut self, message: impl ToString) {
+        self.error = Some(message.to_string());
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(exporter) = EXPORTER.get() else {
+            return;
+        };
+
+        exporter.do_send(ExportSpan(FinishedSpan {
+            trace_id: self.context.trace_id.clone(),
+            span_id: self.context.span_id.clone(),
+            parent_span_id: self.parent_span_id.take(),
+            name: self.name,
+            kind: self.kind,
+            start_unix_nanos: unix_nanos(self.start),
+            end_unix_nanos: unix_nanos(SystemTime::now()),
+            attributes: std::mem::take(&mut self.attributes),
+            error: self.error.take(),
+        }));
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct FinishedSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: &'static str,
+    kind: SpanKind,
+    start_unix_nanos: u64,
+    end_unix_nanos: u64,
+    attributes: BTreeMap<&'static str, String>,
+    error: Option<String>,
+}
+
+impl FinishedSpan {
+    fn to_otlp(&self) -> serde_json::Value {
+        let attributes: Vec<serde_json::Value> = self.attributes.iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+
+        let status = match &self.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 1 }),
+        };
+
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind.otlp_code(),
+            "startTimeUnixNano": self.start_unix_nanos.to_string(),
+            "endTimeUnixNano": self.end_unix_nanos.to_string(),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ExportSpan(FinishedSpan);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Flush;
+
+pub enum SpanSink {
+    File(String),
+    Otlp(String),
+}
+
+impl SpanSink {
+    // TRACE_EXPORTER=file|otlp enables exporting, anything else leaves tracing off
+    pub fn from_env() -> Option<Self> {
+        match env::var("TRACE_EXPORTER").ok()?.trim() {
+            "file" => Some(SpanSink::File(
+                env::var("TRACE_FILE").unwrap_or_else(|_| DEFAULT_TRACE_FILE.to_owned()),
+            )),
+            "otlp" => Some(SpanSink::Otlp(
+                env::var("OTLP_ENDPOINT").unwrap_or_else(|_| DEFAULT_OTLP_ENDPOINT.to_owned()),
+            )),
+            _ => None,
+        }
+    }
+}
+
+pub struct SpanExporter {
+    node_id: u32,
+    sink: SpanSink,
+    buffer: Vec<FinishedSpan>,
+    client: Client,
+}
+
+impl SpanExporter {
+    pub fn new(node_id: u32, sink: SpanSink) -> Self {
+        SpanExporter {
+            node_id,
+            sink,
+            buffer: Vec::with_capacity(MAX_BATCH_SIZE),
+            client: Client::default(),
+        }
+    }
+
+    // Drains the buffer; the returned future completes once the batch is exported
+    fn export_buffered(&mut self) -> LocalBoxFuture<'static, ()> {
+        if self.buffer.is_empty() {
+            return Box::pin(future::ready(()));
+        }
+
+        let spans = std::mem::take(&mut self.buffer);
+
+        match &self.sink {
+            SpanSink::File(path) => {
+                if let Err(err) = write_span_lines(path, &spans) {
+                    error!("Failed to write {} spans to {}: {}", spans.len(), path, err);
+                }
+                Box::pin(future::ready(()))
+            }
+            SpanSink::Otlp(url) => {
+                let body = json!({
+                    "resourceSpans": [{
+                        "resource": {
+                            "attributes": [
+                                { "key": "service.name", "value": { "stringValue": "bigo" } },
+                                { "key": "node.id", "value": { "stringValue": self.node_id.to_string() } },
+                            ]
+                        },
+                        "scopeSpans": [{
+                            "scope": { "name": "rs" },
+                            "spans": spans.iter().map(|span| span.to_otlp()).collect::<Vec<_>>(),
+                        }]
+                    }]
+                });
+
+                let url = url.clone();
+                let request = self.client.post(url.clone()).send_json(&body);
+                Box::pin(async move {
+                    match request.await {
+                        Ok(res) if !res.status().is_success() => {
+                            error!("OTLP collector at {} rejected spans: {}", url, res.status());
+                        }
+                        Err(err) => error!("Failed to export spans to {}: {}", url, err),
+                        _ => {}
+                    }
+                })
+            }
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        ctx.spawn(self.export_buffered().into_actor(self));
+    }
+}
+
+fn write_span_lines(path: &str, spans: &[FinishedSpan]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for span in spans {
+        let line = serde_json::to_string(span)?;
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+impl Actor for SpanExporter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("SpanExporter started");
+        ctx.run_interval(FLUSH_INTERVAL, |act, ctx| act.flush(ctx));
+    }
+}
+
+impl Handler<ExportSpan> for SpanExporter {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExportSpan, ctx: &mut Context<Self>) -> Self::Result {
+        self.buffer.push(msg.0);
+        if self.buffer.len() >= MAX_BATCH_SIZE {
+            self.flush(ctx);
+        }
+    }
+}
+
+impl Handler<Flush> for SpanExporter {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Flush, _ctx: &mut Context<Self>) -> Self::Result {
+        self.export_buffered()
+    }
+}
+
+// Starts the exporter configured through the environment, if any
+pub fn init(node_id: u32) {
+    let Some(sink) = SpanSink::from_env() else {
+        info!("Tracing disabled, set TRACE_EXPORTER=file|otlp to export spans");
+        return;
+    };
+
+    let exporter = SpanExporter::new(node_id, sink).start();
+    if EXPORTER.set(exporter).is_err() {
+        error!("Span exporter already initialised");
+    }
+}
+
+// Exports whatever is still buffered, used on shutdown
+pub async fn flush() {
+    if let Some(exporter) = EXPORTER.get() {
+        if let Err(err) = exporter.send(Flush).await {
+            error!("Failed to flush spans: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent_roundtrip() {
+        let ctx = TraceContext::new_root();
+        let parsed = TraceContext::from_traceparent(&ctx.to_traceparent()).unwrap();
+
+        assert_eq!(parsed.trace_id, ctx.trace_id);
+        assert_eq!(parsed.span_id, ctx.span_id);
+    }
+
+    #[test]
+    fn test_traceparent_rejects_malformed_values() {
+        assert!(TraceContext::from_traceparent("").is_none());
+        assert!(TraceContext::from_traceparent("00-abc-def-01").is_none());
+        assert!(TraceContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e473z-00f067aa0ba902b7-01").is_none());
+    }
+
+    #[test]
+    fn test_child_span_inherits_trace_id() {
+        let parent = TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let span = Span::start("child", Some(&parent), SpanKind::Client);
+
+        assert_eq!(span.context().trace_id, parent.trace_id);
+        assert_ne!(span.context().span_id, parent.span_id);
+        assert_eq!(span.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+    }
+
+    #[test]
+    fn test_deadline_propagates() {
+        let mut span = Span::start("root", None, SpanKind::Server);
+        assert!(span.context().deadline.is_none());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        span.set_deadline(deadline);
+        span.set_deadline(deadline + Duration::from_secs(5));
+        let child = Span::start("child", Some(&span.context()), SpanKind::Client);
+        assert_eq!(child.context().deadline, Some(deadline));
+
+        let mut request = Request::new(());
+        child.context().inject(&mut request);
+        let timeout = grpc_timeout(&request).unwrap();
+        assert!(timeout <= Duration::from_secs(5) && timeout > Duration::from_secs(4));
+        assert!(TraceContext::from_metadata(&request).unwrap().remaining().unwrap() <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(parse_grpc_timeout("10x"), None);
+        assert_eq!(parse_grpc_timeout("1234567890m"), None);
+    }
+}
@@ -8,6 +8,22 @@ pub fn parse_socket_addr(addr_str: &str) -> Result<(String, u16), Box<dyn std::e
     Ok((ip, port))
 }
 
+// Cursors are opaque to clients: the hex-encoded last location id of the previous page
+pub fn encode_cursor(location_id: &str) -> String {
+    location_id.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<String> {
+    if cursor.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| cursor.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
 pub fn get_owner_node_id(location_id: String) -> u32 {
     let mut owner_id = 0;
     for (_, c) in location_id.chars().enumerate() {
@@ -15,3 +31,32 @@ pub fn get_owner_node_id(location_id: String) -> u32 {
     }
    return owner_id;
 }
+
+// The owner keeps the record, shard `index` of the 6 lives on the index-th other node
+pub fn shard_node_id(owner_id: u32, index: usize) -> u32 {
+    if index as u32 >= owner_id {
+        index as u32 + 1
+    } else {
+        index as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = encode_cursor("loc-42/ü");
+        assert!(cursor.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(decode_cursor(&cursor).as_deref(), Some("loc-42/ü"));
+        assert_eq!(decode_cursor("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_decode_cursor_rejects_garbage() {
+        assert!(decode_cursor("abc").is_none());
+        assert!(decode_cursor("zz").is_none());
+        assert!(decode_cursor("ff").is_none());
+    }
+}
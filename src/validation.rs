@@ -0,0 +1,282 @@
+use std::env;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::dto::{LocationPatch, LocationStats, ShardError};
+
+const DEFAULT_MAX_LOCATION_ID_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Rule {
+    NotEmpty,
+    MaxLength(usize),
+    Uuid,
+    Finite,
+    Between(f64, f64),
+}
+
+impl Rule {
+    fn check_str(&self, value: &str) -> Option<String> {
+        match self {
+            Rule::NotEmpty if value.is_empty() => Some("must not be empty".to_owned()),
+            Rule::MaxLength(max) if value.len() > *max => Some(format!("must be at most {} bytes long", max)),
+            Rule::Uuid if Uuid::parse_str(value).is_err() => Some("must be a UUID".to_owned()),
+            _ => None,
+        }
+    }
+
+    fn check_number(&self, value: f64) -> Option<String> {
+        match self {
+            Rule::Finite if !value.is_finite() => Some("must be a finite number".to_owned()),
+            Rule::Between(min, max) if value.is_finite() && (value < *min || value > *max) => {
+                Some(format!("must be between {} and {}", min, max))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Range {
+    pub fn new(min: f64, max: f64) -> Self {
+        Range { min, max }
+    }
+
+    // Parses `min..max`, either side may be left empty to leave it unbounded
+    pub fn parse(value: &str) -> Option<Self> {
+        let (min, max) = value.trim().split_once("..")?;
+        let bound = |s: &str, default: f64| -> Option<f64> {
+            if s.trim().is_empty() { Some(default) } else { s.trim().parse().ok() }
+        };
+        let range = Range::new(bound(min, f64::NEG_INFINITY)?, bound(max, f64::INFINITY)?);
+        (range.min <= range.max).then_some(range)
+    }
+
+    fn from_env(key: &str, default: Range) -> Self {
+        match env::var(key) {
+            Ok(value) => Range::parse(&value).unwrap_or_else(|| {
+                warn!("Ignoring invalid range {}={}, expected min..max", key, value);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    fn rule(&self) -> Rule {
+        Rule::Between(self.min, self.max)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidationConfig {
+    pub max_location_id_len: usize,
+    pub seismic_activity: Range,
+    pub temperature_c: Range,
+    pub radiation_level: Range,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            max_location_id_len: DEFAULT_MAX_LOCATION_ID_LEN,
+            seismic_activity: Range::new(f64::NEG_INFINITY, f64::INFINITY),
+            temperature_c: Range::new(-273.15, f64::INFINITY),
+            radiation_level: Range::new(0.0, f64::INFINITY),
+        }
+    }
+}
+
+impl ValidationConfig {
+    pub fn from_env() -> Self {
+        let default = ValidationConfig::default();
+        ValidationConfig {
+            max_location_id_len: env::var("VALIDATION_MAX_LOCATION_ID_LEN")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(default.max_location_id_len),
+            seismic_activity: Range::from_env("VALIDATION_SEISMIC_ACTIVITY_RANGE", default.seismic_activity),
+            temperature_c: Range::from_env("VALIDATION_TEMPERATURE_C_RANGE", default.temperature_c),
+            radiation_level: Range::from_env("VALIDATION_RADIATION_LEVEL_RANGE", default.radiation_level),
+        }
+    }
+}
+
+// Collects every failing field instead of stopping at the first one
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn str(&mut self, field: &str, value: &str, rules: &[Rule]) -> &mut Self {
+        if let Some(message) = rules.iter().find_map(|rule| rule.check_str(value)) {
+            self.errors.push(FieldError { field: field.to_owned(), message });
+        }
+        self
+    }
+
+    pub fn number(&mut self, field: &str, value: f64, rules: &[Rule]) -> &mut Self {
+        if let Some(message) = rules.iter().find_map(|rule| rule.check_number(value)) {
+            self.errors.push(FieldError { field: field.to_owned(), message });
+        }
+        self
+    }
+
+    pub fn finish(&mut self) -> Result<(), ShardError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ShardError::ValidationError(std::mem::take(&mut self.errors)))
+        }
+    }
+}
+
+pub trait Validate {
+    fn validate(&self, config: &ValidationConfig) -> Result<(), ShardError>;
+}
+
+impl Validate for LocationStats {
+    fn validate(&self, config: &ValidationConfig) -> Result<(), ShardError> {
+        Validator::default()
+            .str("id", &self.id, &[Rule::Uuid])
+            .number("seismic_activity", self.seismic_activity, &[Rule::Finite, config.seismic_activity.rule()])
+            .number("temperature_c", self.temperature_c, &[Rule::Finite, config.temperature_c.rule()])
+            .number("radiation_level", self.radiation_level, &[Rule::Finite, config.radiation_level.rule()])
+            .finish()
+    }
+}
+
+impl Validate for LocationPatch {
+    fn validate(&self, config: &ValidationConfig) -> Result<(), ShardError> {
+        let mut validator = Validator::default();
+        if self.is_empty() {
+            validator.errors.push(FieldError { field: "body".to_owned(), message: "must set at least one field".to_owned() });
+        }
+        if let Some(id) = &self.id {
+            validator.str("id", id, &[Rule::Uuid]);
+        }
+        if let Some(value) = self.seismic_activity {
+            validator.number("seismic_activity", value, &[Rule::Finite, config.seismic_activity.rule()]);
+        }
+        if let Some(value) = self.temperature_c {
+            validator.number("temperature_c", value, &[Rule::Finite, config.temperature_c.rule()]);
+        }
+        if let Some(value) = self.radiation_level {
+            validator.number("radiation_level", value, &[Rule::Finite, config.radiation_level.rule()]);
+        }
+        validator.finish()
+    }
+}
+
+pub fn validate_location_id(location_id: &str, config: &ValidationConfig) -> Result<(), ShardError> {
+    Validator::default()
+        .str("location_id", location_id, &[Rule::NotEmpty, Rule::MaxLength(config.max_location_id_len)])
+        .finish()
+}
+
+pub fn validate_write(location_id: &str, stats: &impl Validate, config: &ValidationConfig) -> Result<(), ShardError> {
+    let mut errors = Vec::new();
+    for result in [validate_location_id(location_id, config), stats.validate(config)] {
+        if let Err(ShardError::ValidationError(fields)) = result {
+            errors.extend(fields);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ShardError::ValidationError(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(id: &str, seismic_activity: f64, temperature_c: f64, radiation_level: f64) -> LocationStats {
+        LocationStats {
+            id: id.to_owned(),
+            seismic_activity,
+            temperature_c,
+            radiation_level,
+        }
+    }
+
+    fn fields(result: Result<(), ShardError>) -> Vec<String> {
+        match result {
+            Err(ShardError::ValidationError(errors)) => errors.into_iter().map(|e| e.field).collect(),
+            Err(err) => panic!("Expected ValidationError, got {}", err),
+            Ok(()) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_valid_write_passes() {
+        let config = ValidationConfig::default();
+        let valid = stats("bac32c52-bb64-476d-a36d-91069bbd8a5e", 2.5, 25.5, 0.001);
+
+        assert!(validate_write("loc-1", &valid, &config).is_ok());
+    }
+
+    #[test]
+    fn test_reports_every_invalid_field() {
+        let config = ValidationConfig::default();
+        let invalid = stats("not-a-uuid", f64::NAN, f64::INFINITY, -1.0);
+
+        assert_eq!(
+            fields(validate_write("", &invalid, &config)),
+            vec!["location_id", "id", "seismic_activity", "temperature_c", "radiation_level"]
+        );
+    }
+
+    #[test]
+    fn test_location_id_length_limit() {
+        let config = ValidationConfig { max_location_id_len: 4, ..ValidationConfig::default() };
+
+        assert!(validate_location_id("abcd", &config).is_ok());
+        assert_eq!(fields(validate_location_id("abcde", &config)), vec!["location_id"]);
+    }
+
+    #[test]
+    fn test_configured_range() {
+        let config = ValidationConfig {
+            temperature_c: Range::parse("-90..60").unwrap(),
+            ..ValidationConfig::default()
+        };
+
+        let hot = stats("bac32c52-bb64-476d-a36d-91069bbd8a5e", 1.0, 61.0, 0.0);
+        assert_eq!(fields(hot.validate(&config)), vec!["temperature_c"]);
+    }
+
+    #[test]
+    fn test_patch_validates_only_present_fields() {
+        let config = ValidationConfig::default();
+        let patch = LocationPatch { temperature_c: Some(-300.0), ..LocationPatch::default() };
+
+        assert_eq!(fields(validate_write("loc-1", &patch, &config)), vec!["temperature_c"]);
+        assert_eq!(fields(LocationPatch::default().validate(&config)), vec!["body"]);
+    }
+
+    #[test]
+    fn test_range_parse() {
+        let open = Range::parse("..10").unwrap();
+        assert_eq!(open.min, f64::NEG_INFINITY);
+        assert_eq!(open.max, 10.0);
+
+        assert!(Range::parse("5..1").is_none());
+        assert!(Range::parse("abc").is_none());
+        assert!(Range::parse("1..x").is_none());
+    }
+}